@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use std::{env, fs, io, path};
+
+use crate::config::PlayConfig;
+use crate::decode::{self, Format, Probe};
+use crate::energy::EnergyMap;
+use crate::loudness::{self, Loudness};
+use crate::wav::{ChannelMap, ChannelPolicy, SampleKind};
+
+// Size and modification time identify an unchanged file.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Stamp {
+    size: u64,
+    mtime_s: u64,
+    mtime_ns: u32,
+}
+
+impl Stamp {
+    fn of(path: &path::Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            size: meta.len(),
+            mtime_s: mtime.as_secs(),
+            mtime_ns: mtime.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    stamp: Stamp,
+    format: Format,
+    kind: SampleKind,
+    n_samples: u32,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    // missing from catalogs written before loudness was measured
+    #[serde(default)]
+    loudness: Option<Loudness>,
+    #[serde(default)]
+    energy: Option<EnergyMap>,
+    // Loudness is measured as the file plays, through this map.
+    #[serde(default)]
+    channel_map: Option<ChannelMap>,
+}
+
+impl Entry {
+    fn new(stamp: Stamp, format: Format, probe: &Probe, channel_map: Option<ChannelMap>) -> Self {
+        Entry {
+            stamp,
+            format,
+            kind: probe.kind,
+            n_samples: probe.n_samples,
+            channels: probe.spec.channels,
+            sample_rate: probe.spec.sample_rate,
+            bits_per_sample: probe.spec.bits_per_sample,
+            loudness: probe.loudness,
+            energy: probe.energy.clone(),
+            channel_map,
+        }
+    }
+
+    fn probe(&self) -> Probe {
+        Probe {
+            n_samples: self.n_samples,
+            spec: hound::WavSpec {
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+                bits_per_sample: self.bits_per_sample,
+                sample_format: match self.kind {
+                    SampleKind::Int(_) => hound::SampleFormat::Int,
+                    SampleKind::Float => hound::SampleFormat::Float,
+                },
+            },
+            kind: self.kind,
+            loudness: self.loudness,
+            energy: self.energy.clone(),
+        }
+    }
+}
+
+// Survey results from earlier runs, keyed by path.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Catalog {
+    entries: BTreeMap<path::PathBuf, Entry>,
+    #[serde(skip)]
+    seen: HashSet<path::PathBuf>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Catalog {
+    // A missing or unreadable catalog is an empty one.
+    pub fn load(path: &path::Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(catalog) => catalog,
+                Err(e) => {
+                    eprintln!("ignoring unreadable catalog {:?}: {}", path, e);
+                    Catalog::default()
+                }
+            },
+            Err(_) => Catalog::default(),
+        }
+    }
+
+    pub fn save(&self, path: &path::Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = serde_json::to_string(self).map_err(io::Error::from)?;
+        // Write then rename, so an interrupted save leaves the old catalog.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // When measurements are wanted, an entry without them is out of date,
+    // as is one measured through another channel map,
+    // as when the file's channel policy has changed.
+    fn lookup(
+        &mut self,
+        path: &path::Path,
+        stamp: Stamp,
+        policy: ChannelPolicy,
+        measure: bool,
+    ) -> Option<Probe> {
+        self.seen.insert(path.to_path_buf());
+        match self.entries.get(path) {
+            Some(entry) if entry.stamp == stamp && !measure => Some(entry.probe()),
+            Some(entry)
+                if entry.stamp == stamp
+                    && entry.loudness.is_some()
+                    && entry.energy.is_some()
+                    && entry.channel_map == ChannelMap::resolve(entry.channels, policy) =>
+            {
+                Some(entry.probe())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, path: &path::Path, entry: Entry) {
+        self.entries.insert(path.to_path_buf(), entry);
+        self.dirty = true;
+    }
+
+    // Forget files under the surveyed directories that are gone now.
+    pub fn prune(&mut self, dirs: &[String]) {
+        let n = self.entries.len();
+        let seen = &self.seen;
+        self.entries
+            .retain(|p, _| seen.contains(p) || !dirs.iter().any(|d| p.starts_with(d)));
+        if self.entries.len() != n {
+            self.dirty = true;
+        }
+    }
+
+    pub fn show(&self) {
+        for (path, entry) in self.entries.iter() {
+            let (lufs, peak_db) = match entry.loudness {
+                Some(Loudness { lufs, peak }) => (
+                    lufs.map_or("-".to_string(), |l| format!("{:.1}", l)),
+                    format!("{:.1}", 20.0 * peak.log10()),
+                ),
+                None => ("?".to_string(), "?".to_string()),
+            };
+            println!(
+                "{:?} {:?} {:?} channels:{} rate:{} seconds:{:.1} lufs:{} peak_db:{}",
+                path,
+                entry.format,
+                entry.kind,
+                entry.channels,
+                entry.sample_rate,
+                entry.n_samples as f32 / entry.sample_rate as f32,
+                lufs,
+                peak_db
+            );
+        }
+        println!("{} files in catalog", self.entries.len());
+    }
+}
+
+// Probe a file, using the catalog when the file has not changed.
+// Measuring decodes the whole file, so it is done only when asked for,
+// and never for files the channel policy cannot play.
+pub fn probe(
+    catalog: &Mutex<Catalog>,
+    path: &path::Path,
+    format: Format,
+    policy: ChannelPolicy,
+    measure: bool,
+) -> Option<Probe> {
+    let stamp = Stamp::of(path)?;
+    if let Some(probe) = catalog.lock().unwrap().lookup(path, stamp, policy, measure) {
+        return Some(probe);
+    }
+    let mut probe = decode::probe(path, format)?;
+    let channel_map = match ChannelMap::resolve(probe.spec.channels, policy) {
+        Some(channel_map) if measure => Some(channel_map),
+        _ => None,
+    };
+    if let Some(channel_map) = channel_map {
+        let (loudness, energy) = loudness::measure(path, format, &probe, channel_map)?;
+        probe.loudness = Some(loudness);
+        probe.energy = Some(energy);
+    }
+    catalog
+        .lock()
+        .unwrap()
+        .insert(path, Entry::new(stamp, format, &probe, channel_map));
+    Some(probe)
+}
+
+pub fn default_path() -> Option<path::PathBuf> {
+    let cache = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => path::PathBuf::from(dir),
+        None => path::PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("acoustic-walk").join("catalog.json"))
+}
+
+// Bring the catalog up to date with the configured directories
+// without playing anything.
+pub fn update(cfg: PlayConfig, rebuild: bool) {
+    let catalog_path = match cfg.catalog.clone() {
+        Some(p) => p,
+        None => {
+            eprintln!("no catalog file is configured");
+            return;
+        }
+    };
+    if rebuild && catalog_path.exists() {
+        fs::remove_file(&catalog_path).expect("removing old catalog");
+    }
+    let params = crate::params::Params::new(&cfg);
+    let n_wavs = crate::engine::survey_all(cfg, &params).len();
+    println!(
+        "catalog {:?} describes {} usable files",
+        catalog_path, n_wavs
+    );
+}
+
+pub fn show(catalog_path: &path::Path) {
+    Catalog::load(catalog_path).show();
+}
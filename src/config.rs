@@ -1,16 +1,171 @@
-use clap::{App, Arg, SubCommand};
-use std::collections::HashSet;
+use crate::catalog;
+use crate::energy::Placement;
+use crate::engine::{
+    DEFAULT_CEILING_DB, DEFAULT_FADE_IN_MS, DEFAULT_FADE_OUT_MS, DEFAULT_N_PRODUCERS,
+    DEFAULT_SAMPLE_RATE, MIN_SAMPLE_RATE,
+};
+use crate::grain::{ClipPolicy, GrainConfig, LayerConfig};
+use crate::output::DEFAULT_RING_MS;
+use crate::pan::Motion;
+use crate::params::Params;
+use crate::pitch::Pitch;
+use crate::schedule::Spacing;
+use crate::wav::ChannelPolicy;
+use crate::window::WindowShape;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
 use std::io::prelude::*;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::{fs, io, path};
 
+const DEFAULT_RINGBUF_N_ENTRIES: usize = 1024;
+
 #[derive(Clone)]
 pub struct PlayConfig {
     pub excluded_wavs: HashSet<std::path::PathBuf>,
     pub dirs: Vec<String>,
     pub cap_ms: Option<u32>,
     pub grain_ms: Option<u32>,
-    pub use_jack: bool,
+    pub density: Option<f32>, // grains per second, None for back to back
+    pub seed: Option<u64>,
+    pub channel_policy: ChannelPolicy,
+    pub dir_channel_policies: Vec<(path::PathBuf, ChannelPolicy)>,
+    pub catalog: Option<path::PathBuf>,
+    pub rescan_secs: Option<u32>,
+    pub control_socket: Option<path::PathBuf>,
+    pub osc_listen: Option<SocketAddr>,
+    pub scenes: Vec<Scene>,
+    pub layers: Vec<Layer>,
+    pub events_osc: Option<SocketAddr>,
+    pub events_json: Option<String>,
+    pub ring_ms: u32,
+    pub gain_db: f32,
+    pub fade_in_ms: u32,
+    pub fade_out_ms: u32,
+    pub ceiling_db: f32,
+    pub n_producers: u32,
+    pub grain: GrainConfig,
+}
+
+// The settings an EngineBuilder starts from.
+impl Default for PlayConfig {
+    fn default() -> Self {
+        PlayConfig {
+            excluded_wavs: HashSet::new(),
+            dirs: Vec::new(),
+            cap_ms: None,
+            grain_ms: None,
+            density: None,
+            seed: None,
+            channel_policy: ChannelPolicy::Downmix,
+            dir_channel_policies: Vec::new(),
+            catalog: catalog::default_path(),
+            rescan_secs: None,
+            control_socket: None,
+            osc_listen: None,
+            scenes: Vec::new(),
+            layers: Vec::new(),
+            events_osc: None,
+            events_json: None,
+            ring_ms: DEFAULT_RING_MS,
+            gain_db: 0.0,
+            fade_in_ms: DEFAULT_FADE_IN_MS,
+            fade_out_ms: DEFAULT_FADE_OUT_MS,
+            ceiling_db: DEFAULT_CEILING_DB,
+            n_producers: DEFAULT_N_PRODUCERS,
+            grain: GrainConfig::default(),
+        }
+    }
+}
+
+impl PlayConfig {
+    // The most specific directory policy wins over the default.
+    pub fn channel_policy(&self, wav: &path::Path) -> ChannelPolicy {
+        self.dir_channel_policies
+            .iter()
+            .filter(|(dir, _)| wav.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count())
+            .map(|(_, policy)| *policy)
+            .unwrap_or(self.channel_policy)
+    }
+
+    // Only loudness matching and placement by energy
+    // need each file decoded whole during the survey.
+    pub fn measures_loudness(&self) -> bool {
+        self.grain.target_lufs.is_some() || self.grain.placement != Placement::Uniform
+    }
+
+    // Layers take the grain streams in order,
+    // and any streams beyond them use the run-wide settings.
+    pub fn layer_config(&self, stream: u32) -> LayerConfig {
+        let mut first = 0;
+        for layer in self.layers.iter() {
+            if stream < first + layer.n_grains {
+                return layer.grain;
+            }
+            first += layer.n_grains;
+        }
+        self.grain.layer
+    }
+}
+
+// A group of grain streams sharing settings that differ from other groups.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub name: Option<String>,
+    pub n_grains: u32,
+    pub grain: LayerConfig,
+}
+
+// A [[layer]] table, whose settings default to the run-wide ones.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLayer {
+    name: Option<String>,
+    n_grains: u32,
+    window: Option<String>,
+    pitch: Option<String>,
+    reverse_prob: Option<f32>,
+    pan: Option<String>,
+    width: Option<String>,
+}
+
+// A named set of live settings, recalled together at runtime.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub name: String,
+    pub grain_ms: Option<u32>,
+    pub n_grains: Option<u32>,
+    pub gain_db: Option<f32>,
+    pub len_cap_ms: Option<u32>,
+    pub density: Option<f32>,
+}
+
+#[derive(Clone)]
+pub enum Sink {
+    Cpal(CpalConfig),
+    Jack,
+    Wav(RenderConfig),
+}
+
+// Output device settings for cpal; JACK makes its own choices.
+#[derive(Clone)]
+pub struct CpalConfig {
+    pub device: Option<String>,
+    pub sample_rate: Option<usize>,
+    pub buffer_size: Option<u32>,
+    pub dither: bool,
+}
+
+#[derive(Clone)]
+pub struct RenderConfig {
+    pub outfile: path::PathBuf,
+    pub duration_ms: u32,
+    pub sample_rate: usize,
 }
 
 #[derive(Clone)]
@@ -19,26 +174,103 @@ pub struct RingbufConfig {
     pub n_entries: usize,
 }
 
+#[derive(Clone)]
+pub enum CatalogAction {
+    Show(path::PathBuf),
+    Update(PlayConfig),
+    Rebuild(PlayConfig),
+}
+
 #[derive(Clone)]
 pub enum Config {
-    Play(PlayConfig),
+    Play(PlayConfig, Sink),
+    Catalog(CatalogAction),
+    Devices,
     Buf(RingbufConfig),
     Cpal,
 }
 
-pub fn make_config() -> Config {
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(path::PathBuf, io::Error),
+    Toml(path::PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "reading {:?}: {}", path, e),
+            ConfigError::Toml(path, e) => write!(f, "parsing {:?}: {}", path, e),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Settings from a --config TOML file, all optional.
+// Command-line flags take precedence over these.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    dirs: Option<Vec<String>>,
+    exclude: Option<path::PathBuf>,
+    excluded_wavs: Option<Vec<path::PathBuf>>,
+    len_cap_ms: Option<u32>,
+    grain_ms: Option<u32>,
+    density: Option<f32>,
+    spacing: Option<String>,
+    seed: Option<u64>,
+    channels: Option<String>,
+    dir_channels: Option<HashMap<path::PathBuf, String>>,
+    catalog: Option<path::PathBuf>,
+    rescan_secs: Option<u32>,
+    control_socket: Option<path::PathBuf>,
+    osc_listen: Option<String>,
+    scene: Option<Vec<Scene>>,
+    layer: Option<Vec<FileLayer>>,
+    window: Option<String>,
+    pitch: Option<String>,
+    reverse_prob: Option<f32>,
+    pan: Option<String>,
+    width: Option<String>,
+    events_osc: Option<String>,
+    events_json: Option<String>,
+    ring_ms: Option<u32>,
+    gain_db: Option<f32>,
+    fade_in_ms: Option<u32>,
+    fade_out_ms: Option<u32>,
+    ceiling_db: Option<f32>,
+    dither: Option<bool>,
+    device: Option<String>,
+    buffer_size: Option<u32>,
+    use_jack: Option<bool>,
+    sample_rate: Option<usize>,
+    n_grains: Option<u32>,
+    n_producers: Option<u32>,
+    wav_max_ttl: Option<u32>,
+    min_grain_size_fraction: Option<f32>,
+    tukey_window_alpha: Option<f32>,
+    grain_buf_n_samples: Option<usize>,
+    clip_policy: Option<String>,
+    target_lufs: Option<f32>,
+    placement: Option<String>,
+    silence_db: Option<f32>,
+}
+
+pub fn make_config() -> Result<Config, ConfigError> {
     let matches = App::new("acouwalk")
         .author("Ed.Cashin@acm.org")
         .about("stereo granular audio streamer")
         .subcommand(SubCommand::with_name("cpal"))
+        .subcommand(
+            SubCommand::with_name("devices")
+                .about("List audio hosts, output devices and their supported configs"),
+        )
         .subcommand(
             SubCommand::with_name("play")
-                .arg(Arg::from_usage(
-                    "-c --len-cap=[INT] 'Cap on WAV length in ms as used for selection'",
-                ))
-                .arg(Arg::from_usage(
-                    "-e --exclude=[FILE] 'Read excluded WAVs from file'",
-                ))
+                .args(&walk_args())
                 .arg(
                     Arg::with_name("jack")
                         .long("--use-jack")
@@ -46,14 +278,43 @@ pub fn make_config() -> Config {
                         .takes_value(false),
                 )
                 .arg(Arg::from_usage(
-                    "-g --grain-ms=[INT] 'Milliseconds for minimum grain length'",
+                    "--ring-ms=[MS] 'Audio buffered between the mixer and the output callback'",
                 ))
-                .arg(
-                    Arg::with_name("dirs")
-                        .required(true)
-                        .min_values(1)
-                        .help("<WAV-directory>..."),
-                ),
+                .arg(Arg::from_usage(
+                    "--dither 'Add TPDF dither for 16-bit integer cpal devices'",
+                ))
+                .arg(Arg::from_usage(
+                    "--device=[NAME] 'cpal output device, as listed by the devices subcommand'",
+                ))
+                .arg(Arg::from_usage(
+                    "--sample-rate=[HZ] 'cpal output sample rate'",
+                ))
+                .arg(Arg::from_usage(
+                    "--buffer-size=[FRAMES] 'cpal output buffer size'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("render")
+                .args(&walk_args())
+                .arg(Arg::from_usage(
+                    "-o --output=<FILE> 'WAV file to write the rendered stream to'",
+                ))
+                .arg(Arg::from_usage(
+                    "-d --duration=<SECS> 'Length of the rendered stream in seconds'",
+                ))
+                .arg(Arg::from_usage(
+                    "-r --sample-rate=[INT] 'Sample rate of the rendered WAV'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("catalog")
+                .about("Inspect or refresh the cache of surveyed file descriptions")
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .arg(Arg::from_usage("--catalog=[FILE] 'Catalog file to show'")),
+                )
+                .subcommand(SubCommand::with_name("update").args(&walk_args()))
+                .subcommand(SubCommand::with_name("rebuild").args(&walk_args())),
         )
         .subcommand(
             SubCommand::with_name("ringbuf")
@@ -67,64 +328,529 @@ pub fn make_config() -> Config {
         .get_matches();
 
     match matches.subcommand() {
-        ("cpal", Some(_)) => Config::Cpal,
+        ("cpal", Some(_)) => Ok(Config::Cpal),
+        ("devices", Some(_)) => Ok(Config::Devices),
+        ("catalog", Some(matches)) => match matches.subcommand() {
+            ("show", Some(matches)) => {
+                let catalog = match matches.value_of("catalog") {
+                    Some(p) => path::PathBuf::from(p),
+                    None => catalog::default_path().ok_or_else(|| {
+                        ConfigError::Invalid("no default catalog location".to_string())
+                    })?,
+                };
+                Ok(Config::Catalog(CatalogAction::Show(catalog)))
+            }
+            ("update", Some(matches)) => {
+                let file_cfg = file_config(matches)?;
+                let cfg = play_config(matches, file_cfg)?;
+                Ok(Config::Catalog(CatalogAction::Update(cfg)))
+            }
+            ("rebuild", Some(matches)) => {
+                let file_cfg = file_config(matches)?;
+                let cfg = play_config(matches, file_cfg)?;
+                Ok(Config::Catalog(CatalogAction::Rebuild(cfg)))
+            }
+            _ => Err(ConfigError::Invalid(
+                "catalog needs one of show, update or rebuild".to_string(),
+            )),
+        },
         ("ringbuf", Some(matches)) => {
             let trigfile = if let Some(trigfile) = matches.value_of("trigger-file") {
-                path::PathBuf::from_str(trigfile).expect("path for trigger file")
-            } else {
-                path::PathBuf::from_str("acouwalk.show").expect("path for default trigfile")
-            };
-            let n_entries = if let Some(n) = matches.value_of("n-entries") {
-                n.parse::<usize>().unwrap()
+                path::PathBuf::from(trigfile)
             } else {
-                crate::ringbuf::DEFAULT_N_ENTRIES
+                path::PathBuf::from("acouwalk.show")
             };
-            Config::Buf(RingbufConfig {
+            let n_entries = parse_arg(matches, "n-entries")?.unwrap_or(DEFAULT_RINGBUF_N_ENTRIES);
+            Ok(Config::Buf(RingbufConfig {
                 trigfile,
                 n_entries,
-            })
+            }))
         }
         ("play", Some(matches)) => {
-            let dirs = if let Some(dirs) = matches.values_of("dirs") {
-                dirs.map(String::from).collect()
+            let file_cfg = file_config(matches)?;
+            let sink = if matches.is_present("jack") || file_cfg.use_jack == Some(true) {
+                Sink::Jack
             } else {
-                Vec::new()
+                Sink::Cpal(CpalConfig {
+                    device: matches
+                        .value_of("device")
+                        .map(String::from)
+                        .or_else(|| file_cfg.device.clone()),
+                    sample_rate: check_sample_rate(
+                        parse_arg(matches, "sample-rate")?.or(file_cfg.sample_rate),
+                    )?,
+                    buffer_size: parse_arg(matches, "buffer-size")?.or(file_cfg.buffer_size),
+                    dither: matches.is_present("dither") || file_cfg.dither == Some(true),
+                })
             };
+            Ok(Config::Play(play_config(matches, file_cfg)?, sink))
+        }
+        ("render", Some(matches)) => {
+            let file_cfg = file_config(matches)?;
+            let outfile = matches.value_of("output").expect("output file");
+            let outfile = path::PathBuf::from(outfile);
+            let duration_s: f32 = parse_arg(matches, "duration")?.expect("duration");
+            let sample_rate =
+                check_sample_rate(parse_arg(matches, "sample-rate")?.or(file_cfg.sample_rate))?
+                    .unwrap_or(DEFAULT_SAMPLE_RATE);
+            let sink = Sink::Wav(RenderConfig {
+                outfile,
+                duration_ms: (duration_s * 1000.0) as u32,
+                sample_rate,
+            });
+            Ok(Config::Play(play_config(matches, file_cfg)?, sink))
+        }
+        _ => Err(ConfigError::Invalid(
+            "a subcommand is required (see --help)".to_string(),
+        )),
+    }
+}
 
-            let mut excluded_wavs: HashSet<std::path::PathBuf> = HashSet::new();
-            if let Some(e) = matches.value_of("exclude") {
-                println!("e:{}", e);
-                let f = fs::File::open(e).ok().unwrap();
-                let reader = io::BufReader::new(f);
-                for line in reader.lines() {
-                    let line = line.ok().unwrap();
-                    println!("excluding {}", line);
-                    let path = std::path::Path::new(&line);
-                    excluded_wavs.insert(path.to_path_buf());
-                }
-            }
+// Arguments shared by the subcommands that walk WAV directories.
+fn walk_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::from_usage("--config=[FILE] 'Read play settings from a TOML file'"),
+        Arg::from_usage("-c --len-cap=[INT] 'Cap on WAV length in ms as used for selection'"),
+        Arg::from_usage("-e --exclude=[FILE] 'Read excluded WAVs from file'"),
+        Arg::from_usage("-g --grain-ms=[INT] 'Milliseconds for minimum grain length'"),
+        Arg::from_usage("--density=[GPS] 'Grains started per second across all streams'"),
+        Arg::from_usage(
+            "--spacing=[HOW] 'Grain onset spacing at a given density: poisson or periodic'",
+        ),
+        Arg::from_usage("-s --seed=[INT] 'Seed for reproducible selection and grain placement'"),
+        Arg::from_usage(
+            "--channels=[POLICY] 'Multichannel mapping: downmix (default) or a pair like 0,1'",
+        ),
+        Arg::with_name("dir-channels")
+            .long("dir-channels")
+            .value_name("DIR=POLICY")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Channel mapping for WAVs under DIR"),
+        Arg::from_usage("--catalog=[FILE] 'Cache of surveyed file descriptions'"),
+        Arg::from_usage("--no-catalog 'Survey every file without a cache'"),
+        Arg::from_usage("--rescan-secs=[SECS] 'Look for added and removed files this often'"),
+        Arg::from_usage("--control=[SOCKET] 'Accept live setting changes on a Unix socket'"),
+        Arg::from_usage("--osc=[ADDR] 'Accept OSC messages on a UDP address like 127.0.0.1:9000'"),
+        Arg::from_usage(
+            "--events-osc=[ADDR] 'Send an OSC message for each grain to a UDP address'",
+        ),
+        Arg::from_usage(
+            "--events-json=[TARGET] 'Write each grain as JSON to a file or unix:SOCKET'",
+        ),
+        Arg::from_usage("--gain-db=[DB] 'Master gain at startup'"),
+        Arg::from_usage("--fade-in-ms=[MS] 'Fade up from silence over this long at startup'"),
+        Arg::from_usage(
+            "--fade-out-ms=[MS] 'Fade to silence over this long when signalled to stop'",
+        ),
+        Arg::from_usage("--ceiling-db=[DBFS] 'Output limiter ceiling'"),
+        Arg::from_usage(
+            "--clip-policy=[POLICY] 'For grains reaching full scale: mute, attenuate or retoss'",
+        ),
+        Arg::from_usage("--target-lufs=[LUFS] 'Gain-match each file to this loudness'"),
+        Arg::from_usage(
+            "--placement=[HOW] 'Grain placement within files: uniform, avoid or weight'",
+        ),
+        Arg::from_usage("--silence-db=[DB] 'Level below which placement treats audio as silent'"),
+        Arg::from_usage("--n-grains=[INT] 'Number of grain streams mixed together'"),
+        Arg::from_usage("--n-producers=[INT] 'Number of WAV surveyor threads'"),
+        Arg::from_usage("--wav-max-ttl=[INT] 'Upper bound on grains taken from one WAV'"),
+        Arg::from_usage("--min-grain-fraction=[FLOAT] 'Shortest grain as a fraction of grain-ms'"),
+        Arg::from_usage("--tukey-alpha=[FLOAT] 'Tapered fraction of the Tukey grain window'"),
+        Arg::from_usage(
+            "--window=[SHAPE] 'Grain window: tukey, hann, gaussian, trapezoid or percussive'",
+        ),
+        Arg::from_usage(
+            "--pitch=[SHIFT] 'Grain pitch shift in semitones: N, Nc for cents, uniform:LOW,HIGH or normal:MEAN,SD'",
+        ),
+        Arg::from_usage("--reverse-prob=[P] 'Chance from 0 to 1 of a grain playing backwards'"),
+        Arg::from_usage(
+            "--pan=[PAN] 'Grain pan from -1 to 1: a value, uniform:LOW,HIGH or sweep:FROM,TO,SECS'",
+        ),
+        Arg::from_usage(
+            "--width=[WIDTH] 'Grain stereo width from 0 to 1, given like --pan'",
+        ),
+        Arg::from_usage("--grain-buf-samples=[INT] 'Samples per buffer sent by grain makers'"),
+        Arg::with_name("dirs")
+            .multiple(true)
+            .help("<WAV-directory>..."),
+    ]
+}
 
-            let cap_ms = if let Some(c) = matches.value_of("len-cap") {
-                Some(c.parse::<u32>().unwrap())
-            } else {
-                None
-            };
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, ConfigError> {
+    match matches.value_of(name) {
+        None => Ok(None),
+        Some(v) => v
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| ConfigError::Invalid(format!("ill formed value {:?} for --{}", v, name))),
+    }
+}
 
-            let grain_ms = if let Some(ms) = matches.value_of("grain-ms") {
-                Some(ms.parse::<u32>().expect("ill formed grain milliseconds"))
-            } else {
-                None
-            };
+fn check_sample_rate(rate: Option<usize>) -> Result<Option<usize>, ConfigError> {
+    match rate {
+        Some(r) if r < MIN_SAMPLE_RATE => Err(ConfigError::Invalid(format!(
+            "sample rate {} is below the minimum of {} Hz",
+            r, MIN_SAMPLE_RATE
+        ))),
+        _ => Ok(rate),
+    }
+}
+
+fn file_config(matches: &ArgMatches) -> Result<FileConfig, ConfigError> {
+    match matches.value_of("config") {
+        None => Ok(FileConfig::default()),
+        Some(f) => {
+            let path = path::PathBuf::from(f);
+            let text = fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+            toml::from_str(&text).map_err(|e| ConfigError::Toml(path, e))
+        }
+    }
+}
+
+fn read_excluded(e: &path::Path) -> Result<HashSet<path::PathBuf>, ConfigError> {
+    println!("e:{:?}", e);
+    let mut excluded_wavs: HashSet<std::path::PathBuf> = HashSet::new();
+    let f = fs::File::open(e).map_err(|err| ConfigError::Io(e.to_path_buf(), err))?;
+    let reader = io::BufReader::new(f);
+    for line in reader.lines() {
+        let line = line.map_err(|err| ConfigError::Io(e.to_path_buf(), err))?;
+        println!("excluding {}", line);
+        let path = std::path::Path::new(&line);
+        excluded_wavs.insert(path.to_path_buf());
+    }
+    Ok(excluded_wavs)
+}
+
+fn socket_addr(
+    matches: &ArgMatches,
+    name: &str,
+    from_file: Option<String>,
+) -> Result<Option<SocketAddr>, ConfigError> {
+    match parse_arg(matches, name)? {
+        Some(addr) => Ok(Some(addr)),
+        None => match from_file {
+            Some(addr) => addr
+                .parse()
+                .map(Some)
+                .map_err(|_| ConfigError::Invalid(format!("ill formed UDP address {:?}", addr))),
+            None => Ok(None),
+        },
+    }
+}
+
+fn parse_window(w: &str) -> Result<WindowShape, ConfigError> {
+    w.parse::<WindowShape>().map_err(ConfigError::Invalid)
+}
+
+fn parse_pitch(p: &str) -> Result<Pitch, ConfigError> {
+    p.parse::<Pitch>().map_err(ConfigError::Invalid)
+}
+
+fn parse_pan(p: &str) -> Result<Motion, ConfigError> {
+    Motion::parse(p, "pan", -1.0, 1.0).map_err(ConfigError::Invalid)
+}
+
+fn parse_width(w: &str) -> Result<Motion, ConfigError> {
+    Motion::parse(w, "width", 0.0, 1.0).map_err(ConfigError::Invalid)
+}
 
-            let use_jack = matches.value_of("jack").is_some();
-            Config::Play(PlayConfig {
-                excluded_wavs,
-                dirs,
-                cap_ms,
-                grain_ms,
-                use_jack,
-            })
+fn parse_policy(p: &str) -> Result<ChannelPolicy, ConfigError> {
+    p.parse::<ChannelPolicy>().map_err(ConfigError::Invalid)
+}
+
+fn play_config(matches: &ArgMatches, file_cfg: FileConfig) -> Result<PlayConfig, ConfigError> {
+    let dirs: Vec<String> = if let Some(dirs) = matches.values_of("dirs") {
+        dirs.map(String::from).collect()
+    } else {
+        file_cfg.dirs.unwrap_or_default()
+    };
+    if dirs.is_empty() {
+        return Err(ConfigError::Invalid(
+            "no WAV directories given on the command line or in the config file".to_string(),
+        ));
+    }
+
+    let mut excluded_wavs: HashSet<std::path::PathBuf> = HashSet::new();
+    if let Some(wavs) = file_cfg.excluded_wavs {
+        excluded_wavs.extend(wavs);
+    }
+    let exclude = matches
+        .value_of("exclude")
+        .map(path::PathBuf::from)
+        .or(file_cfg.exclude);
+    if let Some(e) = exclude {
+        excluded_wavs.extend(read_excluded(&e)?);
+    }
+
+    let cap_ms = parse_arg(matches, "len-cap")?.or(file_cfg.len_cap_ms);
+    let grain_ms = parse_arg(matches, "grain-ms")?.or(file_cfg.grain_ms);
+    let density = parse_arg(matches, "density")?.or(file_cfg.density);
+    let seed = parse_arg(matches, "seed")?.or(file_cfg.seed);
+
+    let channel_policy = match matches
+        .value_of("channels")
+        .or(file_cfg.channels.as_deref())
+    {
+        Some(p) => parse_policy(p)?,
+        None => ChannelPolicy::Downmix,
+    };
+
+    let mut dir_channel_policies: Vec<(path::PathBuf, ChannelPolicy)> = Vec::new();
+    if let Some(dir_channels) = file_cfg.dir_channels {
+        for (dir, policy) in dir_channels {
+            dir_channel_policies.push((dir, parse_policy(&policy)?));
         }
-        _ => panic!("unrecognized subcommand"),
     }
+    if let Some(values) = matches.values_of("dir-channels") {
+        for v in values {
+            let eq = v.rfind('=').ok_or_else(|| {
+                ConfigError::Invalid(format!("{:?} is not DIR=POLICY for --dir-channels", v))
+            })?;
+            let (dir, policy) = v.split_at(eq);
+            let dir = path::PathBuf::from(dir);
+            dir_channel_policies.retain(|(d, _)| *d != dir);
+            dir_channel_policies.push((dir, parse_policy(&policy[1..])?));
+        }
+    }
+
+    let catalog = if matches.is_present("no-catalog") {
+        None
+    } else {
+        matches
+            .value_of("catalog")
+            .map(path::PathBuf::from)
+            .or(file_cfg.catalog)
+            .or_else(catalog::default_path)
+    };
+
+    let rescan_secs = parse_arg(matches, "rescan-secs")?.or(file_cfg.rescan_secs);
+
+    let control_socket = matches
+        .value_of("control")
+        .map(path::PathBuf::from)
+        .or(file_cfg.control_socket);
+
+    let osc_listen = socket_addr(matches, "osc", file_cfg.osc_listen)?;
+    let scenes = file_cfg.scene.unwrap_or_default();
+    let events_osc = socket_addr(matches, "events-osc", file_cfg.events_osc)?;
+    let events_json = matches
+        .value_of("events-json")
+        .map(String::from)
+        .or(file_cfg.events_json);
+    let ring_ms = parse_arg(matches, "ring-ms")?
+        .or(file_cfg.ring_ms)
+        .unwrap_or(DEFAULT_RING_MS);
+    let gain_db = parse_arg(matches, "gain-db")?
+        .or(file_cfg.gain_db)
+        .unwrap_or(0.0);
+    let fade_in_ms = parse_arg(matches, "fade-in-ms")?
+        .or(file_cfg.fade_in_ms)
+        .unwrap_or(DEFAULT_FADE_IN_MS);
+    let fade_out_ms = parse_arg(matches, "fade-out-ms")?
+        .or(file_cfg.fade_out_ms)
+        .unwrap_or(DEFAULT_FADE_OUT_MS);
+    let ceiling_db = parse_arg(matches, "ceiling-db")?
+        .or(file_cfg.ceiling_db)
+        .unwrap_or(DEFAULT_CEILING_DB);
+
+    let n_producers = parse_arg(matches, "n-producers")?
+        .or(file_cfg.n_producers)
+        .unwrap_or(DEFAULT_N_PRODUCERS);
+    let defaults = GrainConfig::default();
+    let window = match matches.value_of("window").or(file_cfg.window.as_deref()) {
+        Some(w) => parse_window(w)?,
+        None => match parse_arg(matches, "tukey-alpha")?.or(file_cfg.tukey_window_alpha) {
+            Some(alpha) => parse_window(&format!("tukey:{}", alpha))?,
+            None => defaults.layer.window,
+        },
+    };
+    let pitch = match matches.value_of("pitch").or(file_cfg.pitch.as_deref()) {
+        Some(p) => parse_pitch(p)?,
+        None => defaults.layer.pitch,
+    };
+    let reverse_prob = parse_arg(matches, "reverse-prob")?
+        .or(file_cfg.reverse_prob)
+        .unwrap_or(defaults.layer.reverse_prob);
+    let pan = match matches.value_of("pan").or(file_cfg.pan.as_deref()) {
+        Some(p) => parse_pan(p)?,
+        None => defaults.layer.pan,
+    };
+    let width = match matches.value_of("width").or(file_cfg.width.as_deref()) {
+        Some(w) => parse_width(w)?,
+        None => defaults.layer.width,
+    };
+    let mut layers: Vec<Layer> = Vec::new();
+    for layer in file_cfg.layer.unwrap_or_default() {
+        layers.push(Layer {
+            name: layer.name,
+            n_grains: layer.n_grains,
+            grain: LayerConfig {
+                window: match layer.window {
+                    Some(w) => parse_window(&w)?,
+                    None => window,
+                },
+                pitch: match layer.pitch {
+                    Some(p) => parse_pitch(&p)?,
+                    None => pitch,
+                },
+                reverse_prob: layer.reverse_prob.unwrap_or(reverse_prob),
+                pan: match layer.pan {
+                    Some(p) => parse_pan(&p)?,
+                    None => pan,
+                },
+                width: match layer.width {
+                    Some(w) => parse_width(&w)?,
+                    None => width,
+                },
+            },
+        });
+    }
+    let n_grains = parse_arg(matches, "n-grains")?.or(file_cfg.n_grains);
+    let n_grains = if layers.is_empty() {
+        n_grains.unwrap_or(defaults.n_grains)
+    } else {
+        let total = layers.iter().map(|l| l.n_grains).sum();
+        if n_grains.is_some_and(|n| n != total) {
+            return Err(ConfigError::Invalid(format!(
+                "the grain stream count must match the layers' total of {}",
+                total
+            )));
+        }
+        total
+    };
+    let grain = GrainConfig {
+        n_grains,
+        wav_max_ttl: parse_arg(matches, "wav-max-ttl")?
+            .or(file_cfg.wav_max_ttl)
+            .unwrap_or(defaults.wav_max_ttl),
+        min_grain_size_fraction: parse_arg(matches, "min-grain-fraction")?
+            .or(file_cfg.min_grain_size_fraction)
+            .unwrap_or(defaults.min_grain_size_fraction),
+        layer: LayerConfig {
+            window,
+            pitch,
+            reverse_prob,
+            pan,
+            width,
+        },
+        buf_n_samples: parse_arg(matches, "grain-buf-samples")?
+            .or(file_cfg.grain_buf_n_samples)
+            .unwrap_or(defaults.buf_n_samples),
+        clip_policy: match matches
+            .value_of("clip-policy")
+            .or(file_cfg.clip_policy.as_deref())
+        {
+            Some(p) => p.parse::<ClipPolicy>().map_err(ConfigError::Invalid)?,
+            None => defaults.clip_policy,
+        },
+        target_lufs: parse_arg(matches, "target-lufs")?.or(file_cfg.target_lufs),
+        placement: match matches
+            .value_of("placement")
+            .or(file_cfg.placement.as_deref())
+        {
+            Some(p) => p.parse::<Placement>().map_err(ConfigError::Invalid)?,
+            None => defaults.placement,
+        },
+        silence_db: parse_arg(matches, "silence-db")?
+            .or(file_cfg.silence_db)
+            .unwrap_or(defaults.silence_db),
+        spacing: match matches.value_of("spacing").or(file_cfg.spacing.as_deref()) {
+            Some(p) => p.parse::<Spacing>().map_err(ConfigError::Invalid)?,
+            None => defaults.spacing,
+        },
+    };
+
+    let cfg = PlayConfig {
+        excluded_wavs,
+        dirs,
+        cap_ms,
+        grain_ms,
+        density,
+        seed,
+        channel_policy,
+        dir_channel_policies,
+        catalog,
+        rescan_secs,
+        control_socket,
+        osc_listen,
+        scenes,
+        layers,
+        events_osc,
+        events_json,
+        ring_ms,
+        gain_db,
+        fade_in_ms,
+        fade_out_ms,
+        ceiling_db,
+        n_producers,
+        grain,
+    };
+    validate(&cfg)?;
+    Ok(cfg)
+}
+
+fn validate(cfg: &PlayConfig) -> Result<(), ConfigError> {
+    let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+    if cfg.rescan_secs == Some(0) {
+        return invalid("the rescan interval must be positive");
+    }
+    if cfg.ring_ms == 0 {
+        return invalid("the output ring must hold some audio");
+    }
+    if !(cfg.ceiling_db.is_finite() && cfg.ceiling_db <= 0.0) {
+        return invalid("the limiter ceiling must be at or below 0 dBFS");
+    }
+    if cfg.n_producers == 0 {
+        return invalid("there must be at least one WAV surveyor");
+    }
+    if cfg.grain.n_grains == 0 {
+        return invalid("there must be at least one grain stream");
+    }
+    if cfg.grain.wav_max_ttl < 2 {
+        return invalid("the WAV max TTL must be at least 2");
+    }
+    if !(cfg.grain.min_grain_size_fraction > 0.0 && cfg.grain.min_grain_size_fraction <= 1.0) {
+        return invalid("the minimum grain fraction must be in (0, 1]");
+    }
+    if cfg.layers.iter().any(|l| l.n_grains == 0) {
+        return invalid("every layer must have at least one grain stream");
+    }
+    if std::iter::once(&cfg.grain.layer)
+        .chain(cfg.layers.iter().map(|l| &l.grain))
+        .any(|l| !(0.0..=1.0).contains(&l.reverse_prob))
+    {
+        return invalid("the reverse probability must be from 0 to 1");
+    }
+    if cfg
+        .grain
+        .target_lufs
+        .is_some_and(|l| !(l.is_finite() && l < 0.0))
+    {
+        return invalid("the target loudness must be below 0 LUFS");
+    }
+    if !cfg.grain.silence_db.is_finite() {
+        return invalid("the silence level must be a finite number of dB");
+    }
+    if cfg.grain.buf_n_samples < 2 || !cfg.grain.buf_n_samples.is_multiple_of(2) {
+        return invalid("grain buffers must hold a positive, even number of samples");
+    }
+    let params = Params::new(cfg);
+    if let Some(grain_ms) = cfg.grain_ms {
+        params
+            .set_grain_ms(grain_ms)
+            .map_err(ConfigError::Invalid)?;
+    }
+    params
+        .set_gain_db(cfg.gain_db)
+        .map_err(ConfigError::Invalid)?;
+    params
+        .set_density(cfg.density)
+        .map_err(ConfigError::Invalid)?;
+    for scene in cfg.scenes.iter() {
+        params
+            .apply_scene(scene)
+            .map_err(|e| ConfigError::Invalid(format!("scene {:?}: {}", scene.name, e)))?;
+    }
+    Ok(())
 }
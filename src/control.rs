@@ -0,0 +1,168 @@
+use std::io::prelude::*;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::{fs, io, path, thread};
+
+use crate::params::Params;
+
+// Serve a line protocol for changing live settings on a Unix socket.
+//
+//     get
+//     set grain_ms 3000
+//     set n_grains 3
+//     set gain_db -6
+//     set cap_ms 70000      (or none)
+//     set density 40        (grains per second, or none)
+//     scene quiet           (by name or position in the config file)
+//     exclude /path/to/file.wav
+//     include /path/to/file.wav
+//
+// Each command is answered by "ok" or "error: ...",
+// with "get" listing the current settings before its "ok".
+// The socket is removed when the returned Server is dropped.
+pub fn serve(socket: &path::Path, params: Arc<Params>) -> io::Result<Server> {
+    // A socket left behind by an earlier run would make bind fail,
+    // but one that still answers belongs to a running instance.
+    if fs::symlink_metadata(socket).is_ok_and(|meta| meta.file_type().is_socket()) {
+        match UnixStream::connect(socket) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another instance is listening there",
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => remove_socket(socket)?,
+            Err(_) => {}
+        }
+    }
+    let listener = UnixListener::bind(socket)?;
+    println!("control server listening on {:?}", socket);
+    thread::Builder::new()
+        .name("control server".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let params = params.clone();
+                        thread::Builder::new()
+                            .name("control client".to_string())
+                            .spawn(move || {
+                                if let Err(e) = converse(stream, &params) {
+                                    eprintln!("control client: {}", e);
+                                }
+                            })
+                            .expect("spawning control client");
+                    }
+                    Err(e) => eprintln!("control server accepting: {}", e),
+                }
+            }
+        })?;
+    Ok(Server {
+        socket: socket.to_path_buf(),
+    })
+}
+
+// A running control server, whose socket goes away with it.
+pub struct Server {
+    socket: path::PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Err(e) = remove_socket(&self.socket) {
+            eprintln!("removing control socket {:?}: {}", self.socket, e);
+        }
+    }
+}
+
+// Remove the control socket, leaving anything else at its path alone.
+pub fn remove_socket(socket: &path::Path) -> io::Result<()> {
+    match fs::symlink_metadata(socket) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(socket),
+        _ => Ok(()),
+    }
+}
+
+fn converse(stream: UnixStream, params: &Params) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in io::BufReader::new(stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        println!("control command: {}", line);
+        match command(line, params) {
+            Ok(reply) => writeln!(out, "{}ok", reply)?,
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+fn command(line: &str, params: &Params) -> Result<String, String> {
+    let (verb, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    match verb {
+        "get" => Ok(state(params)),
+        "set" => {
+            let mut words = rest.split_whitespace();
+            let (key, value) = match (words.next(), words.next(), words.next()) {
+                (Some(key), Some(value), None) => (key, value),
+                _ => return Err("usage: set KEY VALUE".to_string()),
+            };
+            set(params, key, value)?;
+            Ok(String::new())
+        }
+        "scene" if !rest.is_empty() => {
+            params.recall_scene(rest)?;
+            Ok(String::new())
+        }
+        "exclude" if !rest.is_empty() => {
+            params.exclude(path::PathBuf::from(rest));
+            Ok(String::new())
+        }
+        "include" if !rest.is_empty() => {
+            params.include(path::Path::new(rest));
+            Ok(String::new())
+        }
+        _ => Err(format!("unknown command {:?}", line)),
+    }
+}
+
+fn set(params: &Params, key: &str, value: &str) -> Result<(), String> {
+    let ill_formed = || format!("ill formed value {:?} for {}", value, key);
+    match key {
+        "grain_ms" => params.set_grain_ms(value.parse().map_err(|_| ill_formed())?),
+        "n_grains" => params.set_n_grains(value.parse().map_err(|_| ill_formed())?),
+        "gain_db" => params.set_gain_db(value.parse().map_err(|_| ill_formed())?),
+        "cap_ms" if value == "none" => params.set_cap_ms(None),
+        "cap_ms" => params.set_cap_ms(Some(value.parse().map_err(|_| ill_formed())?)),
+        "density" if value == "none" => params.set_density(None),
+        "density" => params.set_density(Some(value.parse().map_err(|_| ill_formed())?)),
+        _ => Err(format!("unknown setting {:?}", key)),
+    }
+}
+
+fn state(params: &Params) -> String {
+    let or_default = |v: Option<u32>, default: &str| match v {
+        Some(v) => v.to_string(),
+        None => default.to_string(),
+    };
+    format!(
+        "grain_ms {}\nn_grains {}\nmax_n_grains {}\ngain_db {:.1}\ncap_ms {}\ndensity {}\nexcluded {}\nscenes {}\n",
+        or_default(params.grain_ms(), "default"),
+        params.n_grains(),
+        params.max_n_grains(),
+        params.gain_db(),
+        or_default(params.cap_ms(), "none"),
+        params
+            .density()
+            .map_or("none".to_string(), |d| d.to_string()),
+        params.n_excluded(),
+        params.scene_names().join(" "),
+    )
+}
@@ -3,82 +3,312 @@ use rand_distr::Distribution;
 use std::thread;
 use std::time::Duration;
 
+use acoustic_walk::config::CpalConfig;
+use acoustic_walk::engine::DEFAULT_SAMPLE_RATE;
+use acoustic_walk::output::RealtimeOutput;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, Sample, SampleFormat, SampleRate, StreamConfig};
-use crossbeam_channel::{bounded, Receiver, RecvError, Sender};
+use cpal::{
+    BufferSize, Device, Sample, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize,
+};
+use crossbeam_channel::{bounded, Sender};
 
-pub const SAMPLE_RATE: usize = 44100;
+// Integer devices get the f32 mix converted in pieces of this many samples.
+const CONVERT_BUF_N_SAMPLES: usize = 16 * 1024;
 
-pub fn play_to_cpal(done_tx: Sender<()>, samples_rx: Receiver<Vec<f32>>) {
+// A device and stream config negotiated before the engine starts,
+// so grains can be made at the device's sample rate.
+pub struct CpalStream {
+    device: Device,
+    config: StreamConfig,
+    sample_format: SampleFormat,
+}
+
+impl CpalStream {
+    pub fn sample_rate(&self) -> usize {
+        self.config.sample_rate.0 as usize
+    }
+}
+
+pub fn play_to_cpal(
+    stream: CpalStream,
+    done_tx: Sender<()>,
+    mut output: RealtimeOutput,
+    dither: bool,
+) {
     println!("play_to_cpal starting");
-    let mut samples: Vec<f32> = Vec::new();
-    let (cb_done_tx, cb_done_rx) = bounded(0);
-    let mut consumed = 0;
-    let callback = move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
-        let n = data.len();
-        if consumed > SAMPLE_RATE {
-            samples = samples.split_off(consumed);
-            consumed = 0;
-        }
-        if samples.len() - consumed < n {
-            // (2 for stereo)
-            match samples_rx.recv() {
-                Ok(mut new_samples) => {
-                    println!("play received {} stereo samples", new_samples.len() / 2);
-                    samples.append(&mut new_samples)
-                }
-                Err(RecvError) => {
-                    println!("play received EOF from samples channel");
-                    cb_done_tx.send(()).unwrap();
-                    return;
-                }
-            }
-        }
-        let n = std::cmp::min(n, samples.len());
-        data[..n].clone_from_slice(&samples[consumed..(n + consumed)]);
-        consumed += n;
-    };
-    let (device, config, sample_format) = prep_for_stream();
+    let CpalStream {
+        device,
+        config,
+        sample_format,
+    } = stream;
+    let (cb_done_tx, cb_done_rx) = bounded(1);
     let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
     let stream = match sample_format {
-        SampleFormat::F32 => device.build_output_stream(&config, callback, err_fn),
-        SampleFormat::I16 => device.build_output_stream(&config, write_silence::<i16>, err_fn),
-        SampleFormat::U16 => device.build_output_stream(&config, write_silence::<u16>, err_fn),
+        SampleFormat::F32 => device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
+                if !output.fill(data) {
+                    let _ = cb_done_tx.try_send(());
+                }
+            },
+            err_fn,
+        ),
+        SampleFormat::I16 => {
+            let mut converter = Converter::new(dither);
+            device.build_output_stream(
+                &config,
+                move |data: &mut [i16], _info: &cpal::OutputCallbackInfo| {
+                    if !converter.fill(&mut output, data) {
+                        let _ = cb_done_tx.try_send(());
+                    }
+                },
+                err_fn,
+            )
+        }
+        SampleFormat::U16 => {
+            let mut converter = Converter::new(dither);
+            device.build_output_stream(
+                &config,
+                move |data: &mut [u16], _info: &cpal::OutputCallbackInfo| {
+                    if !converter.fill(&mut output, data) {
+                        let _ = cb_done_tx.try_send(());
+                    }
+                },
+                err_fn,
+            )
+        }
     }
     .unwrap();
     stream.play().unwrap();
 
     let _ = cb_done_rx.recv();
     println!("play received playdone message from cpal callback");
+    if let Err(e) = stream.pause() {
+        eprintln!("pausing the output audio stream: {}", e);
+    }
+    drop(stream);
     println!("play_to_cpal is done");
     done_tx.send(()).unwrap();
 }
 
-fn prep_for_stream() -> (Device, StreamConfig, SampleFormat) {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .expect("no output device available");
-    let mut supported_configs_range = device
+// Converts the f32 mix for 16-bit devices, optionally with TPDF dither.
+// Everything is preallocated, so it is safe in the audio callback.
+struct Converter {
+    buf: Vec<f32>,
+    dither: Option<Dither>,
+}
+
+impl Converter {
+    fn new(dither: bool) -> Self {
+        Converter {
+            buf: vec![0.0; CONVERT_BUF_N_SAMPLES],
+            dither: if dither { Some(Dither::new()) } else { None },
+        }
+    }
+
+    fn fill<T: Sample>(&mut self, output: &mut RealtimeOutput, data: &mut [T]) -> bool {
+        let mut more = true;
+        for piece in data.chunks_mut(self.buf.len()) {
+            let buf = &mut self.buf[..piece.len()];
+            more = output.fill(buf) && more;
+            for (out, s) in piece.iter_mut().zip(buf.iter()) {
+                let s = match &mut self.dither {
+                    Some(dither) => s + dither.next(),
+                    None => *s,
+                };
+                *out = T::from(&s.clamp(-1.0, 1.0));
+            }
+        }
+        more
+    }
+}
+
+// Triangular noise spanning plus and minus one 16-bit step,
+// from a xorshift generator that needs no locking or allocation.
+struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    const LSB: f32 = 1.0 / 32768.0;
+
+    fn new() -> Self {
+        Dither { state: 0x9e37_79b9 }
+    }
+
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+
+    fn next(&mut self) -> f32 {
+        (self.uniform() - self.uniform()) * Self::LSB
+    }
+}
+
+fn find_device(name: Option<&str>) -> Result<Device, String> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            return cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| "no output device available".to_string())
+        }
+    };
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(_) => continue,
+        };
+        if let Ok(devices) = host.output_devices() {
+            for device in devices {
+                if device.name().ok().as_deref() == Some(name) {
+                    return Ok(device);
+                }
+            }
+        }
+    }
+    Err(format!(
+        "no output device named {:?} (see the devices subcommand)",
+        name
+    ))
+}
+
+// Pick a stereo output config, preferring f32 samples over 16-bit integers.
+// Without a requested rate, 44100 Hz is used if possible,
+// and otherwise the device's default rate.
+pub fn open(cfg: &CpalConfig) -> Result<CpalStream, String> {
+    let device = find_device(cfg.device.as_deref())?;
+    let configs: Vec<_> = device
         .supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range
-        .find(|c| c.channels() == 2 && c.sample_format() == SampleFormat::F32)
-        .expect("no supported config?!")
-        .with_sample_rate(SampleRate(SAMPLE_RATE as u32));
+        .map_err(|e| format!("querying output configs: {}", e))?
+        .filter(|c| c.channels() == 2)
+        .collect();
+    let supports = |rate: SampleRate| {
+        configs
+            .iter()
+            .any(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+    };
+    let sample_rate = match cfg.sample_rate {
+        Some(rate) => SampleRate(rate as u32),
+        None if supports(SampleRate(DEFAULT_SAMPLE_RATE as u32)) => {
+            SampleRate(DEFAULT_SAMPLE_RATE as u32)
+        }
+        None => device
+            .default_output_config()
+            .map_err(|e| format!("querying default output config: {}", e))?
+            .sample_rate(),
+    };
+    let supported_config = configs
+        .into_iter()
+        .filter(|c| c.min_sample_rate() <= sample_rate && sample_rate <= c.max_sample_rate())
+        .min_by_key(|c| match c.sample_format() {
+            SampleFormat::F32 => 0,
+            SampleFormat::I16 => 1,
+            SampleFormat::U16 => 2,
+        })
+        .ok_or_else(|| {
+            format!(
+                "the output device has no stereo config at {} Hz",
+                sample_rate.0
+            )
+        })?
+        .with_sample_rate(sample_rate);
+    let buffer_size = match cfg.buffer_size {
+        None => BufferSize::Default,
+        Some(n) => match supported_config.buffer_size() {
+            SupportedBufferSize::Range { min, max } if n < *min || *max < n => {
+                return Err(format!(
+                    "buffer size {} is outside the device's range of {} to {}",
+                    n, min, max
+                ))
+            }
+            _ => BufferSize::Fixed(n),
+        },
+    };
     println!("selected config: {:#?}", supported_config);
     let sample_format = supported_config.sample_format();
-    let config = supported_config.into();
-    (device, config, sample_format)
+    let mut config: StreamConfig = supported_config.into();
+    config.buffer_size = buffer_size;
+    Ok(CpalStream {
+        device,
+        config,
+        sample_format,
+    })
+}
+
+pub fn list_devices() {
+    for host_id in cpal::available_hosts() {
+        println!("host {}", host_id.name());
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("  unavailable: {}", e);
+                continue;
+            }
+        };
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        let devices = match host.output_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                println!("  cannot list devices: {}", e);
+                continue;
+            }
+        };
+        for device in devices {
+            let name = device.name().unwrap_or_else(|_| "(unnamed)".to_string());
+            let default = if default_name.as_deref() == Some(name.as_str()) {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("  device {:?}{}", name, default);
+            let configs = match device.supported_output_configs() {
+                Ok(configs) => configs,
+                Err(e) => {
+                    println!("    cannot list configs: {}", e);
+                    continue;
+                }
+            };
+            for c in configs {
+                let buffer = match c.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => {
+                        format!("{}-{} frames", min, max)
+                    }
+                    SupportedBufferSize::Unknown => "unknown".to_string(),
+                };
+                println!(
+                    "    {} channels, {}-{} Hz, {:?}, buffer {}",
+                    c.channels(),
+                    c.min_sample_rate().0,
+                    c.max_sample_rate().0,
+                    c.sample_format(),
+                    buffer
+                );
+            }
+        }
+    }
 }
 
 pub fn cpal_demo() {
-    let (device, config, sample_format) = prep_for_stream();
+    let CpalStream {
+        device,
+        config,
+        sample_format,
+    } = open(&CpalConfig {
+        device: None,
+        sample_rate: None,
+        buffer_size: None,
+        dither: false,
+    })
+    .expect("opening output device");
     let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
     let stream = match sample_format {
-        SampleFormat::F32 => device.build_output_stream(&config, write_noise, err_fn),
-        SampleFormat::I16 => device.build_output_stream(&config, write_silence::<i16>, err_fn),
-        SampleFormat::U16 => device.build_output_stream(&config, write_silence::<u16>, err_fn),
+        SampleFormat::F32 => device.build_output_stream(&config, write_noise::<f32>, err_fn),
+        SampleFormat::I16 => device.build_output_stream(&config, write_noise::<i16>, err_fn),
+        SampleFormat::U16 => device.build_output_stream(&config, write_noise::<u16>, err_fn),
     }
     .unwrap();
     stream.play().unwrap();
@@ -86,7 +316,7 @@ pub fn cpal_demo() {
     thread::sleep(Duration::from_secs(5));
 }
 
-fn write_noise(data: &mut [f32], info: &cpal::OutputCallbackInfo) {
+fn write_noise<T: Sample>(data: &mut [T], info: &cpal::OutputCallbackInfo) {
     println!(
         "write_noise for {} samples with info:{:?}",
         data.len(),
@@ -100,18 +330,6 @@ fn write_noise(data: &mut [f32], info: &cpal::OutputCallbackInfo) {
         if i % 2 == 0 {
             s = unif.sample(&mut rng);
         }
-        *sample = Sample::from(&s);
-    }
-}
-
-fn write_silence<T: Sample>(data: &mut [T], info: &cpal::OutputCallbackInfo) {
-    println!(
-        "write_silence for {} samples with info:{:?}",
-        data.len(),
-        info
-    );
-    for sample in data.iter_mut() {
-        *sample = Sample::from(&0.0);
+        *sample = T::from(&s);
     }
-    println!("write_silence done.");
 }
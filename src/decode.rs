@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::energy::EnergyMap;
+use crate::loudness::Loudness;
+use crate::wav::{self, SampleKind, WavDesc};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Format {
+    Wav,
+    Flac,
+    Ogg,
+}
+
+impl Format {
+    pub fn from_path(path: &path::Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        if ext.eq_ignore_ascii_case("wav") {
+            Some(Format::Wav)
+        } else if ext.eq_ignore_ascii_case("flac") {
+            Some(Format::Flac)
+        } else if ext.eq_ignore_ascii_case("ogg") || ext.eq_ignore_ascii_case("oga") {
+            Some(Format::Ogg)
+        } else {
+            None
+        }
+    }
+}
+
+// What the survey needs to know about any source file.
+// Loudness and energy take decoding the whole file,
+// so headers alone leave them None.
+pub struct Probe {
+    pub n_samples: u32,
+    pub spec: hound::WavSpec,
+    pub kind: SampleKind,
+    pub loudness: Option<Loudness>,
+    pub energy: Option<EnergyMap>,
+}
+
+// Seekable decoding of interleaved samples normalized to [-1.0, 1.0].
+pub trait Decode {
+    fn seek(&mut self, frame: u32);
+    fn read(&mut self, n: usize, too_loud: &mut bool) -> Vec<f32>;
+}
+
+pub fn probe(path: &path::Path, format: Format) -> Option<Probe> {
+    match format {
+        Format::Wav => {
+            let reader = hound::WavReader::open(path).ok()?;
+            let spec = reader.spec();
+            Some(Probe {
+                n_samples: reader.duration(),
+                spec,
+                kind: SampleKind::from_spec(&spec)?,
+                loudness: None,
+                energy: None,
+            })
+        }
+        Format::Flac | Format::Ogg => {
+            let (reader, track_id) = open_compressed(path, format)?;
+            let track = reader.tracks().iter().find(|t| t.id == track_id)?;
+            let params = &track.codec_params;
+            let kind = match (format, params.bits_per_sample) {
+                (Format::Flac, Some(bits)) => SampleKind::Int(bits as u16),
+                _ => SampleKind::Float,
+            };
+            let spec = hound::WavSpec {
+                channels: params.channels?.count() as u16,
+                sample_rate: params.sample_rate?,
+                bits_per_sample: params.bits_per_sample.unwrap_or(32) as u16,
+                sample_format: match kind {
+                    SampleKind::Int(_) => hound::SampleFormat::Int,
+                    SampleKind::Float => hound::SampleFormat::Float,
+                },
+            };
+            Some(Probe {
+                n_samples: params.n_frames? as u32,
+                spec,
+                kind,
+                loudness: None,
+                energy: None,
+            })
+        }
+    }
+}
+
+pub fn open(wav: &WavDesc) -> Option<Box<dyn Decode + Send>> {
+    open_file(&wav.path, wav.format, wav.kind, wav.spec.channels)
+}
+
+pub fn open_file(
+    path: &path::Path,
+    format: Format,
+    kind: SampleKind,
+    n_channels: u16,
+) -> Option<Box<dyn Decode + Send>> {
+    match format {
+        Format::Wav => {
+            let reader = hound::WavReader::open(path).ok()?;
+            Some(Box::new(WavDecode { reader, kind }))
+        }
+        Format::Flac | Format::Ogg => {
+            let (reader, track_id) = open_compressed(path, format)?;
+            let track = reader.tracks().iter().find(|t| t.id == track_id)?;
+            let decoder = symphonia::default::get_codecs()
+                .make(&track.codec_params, &DecoderOptions::default())
+                .ok()?;
+            Some(Box::new(CompressedDecode {
+                reader,
+                decoder,
+                track_id,
+                kind,
+                pending: Vec::new(),
+                skip: 0,
+                n_channels: n_channels as usize,
+            }))
+        }
+    }
+}
+
+fn open_compressed(path: &path::Path, format: Format) -> Option<(Box<dyn FormatReader>, u32)> {
+    let file = fs::File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(match format {
+        Format::Flac => "flac",
+        _ => "ogg",
+    });
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+    let track_id = probed.format.default_track()?.id;
+    Some((probed.format, track_id))
+}
+
+struct WavDecode {
+    reader: hound::WavReader<io::BufReader<fs::File>>,
+    kind: SampleKind,
+}
+
+impl Decode for WavDecode {
+    fn seek(&mut self, frame: u32) {
+        self.reader.seek(frame).ok();
+    }
+
+    fn read(&mut self, n: usize, too_loud: &mut bool) -> Vec<f32> {
+        wav::read_samples(&mut self.reader, self.kind, n, too_loud)
+    }
+}
+
+struct CompressedDecode {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    kind: SampleKind,
+    pending: Vec<f32>,
+    // frames to drop after a coarse seek
+    skip: u64,
+    n_channels: usize,
+}
+
+impl Decode for CompressedDecode {
+    fn seek(&mut self, frame: u32) {
+        self.pending.clear();
+        self.skip = 0;
+        let to = SeekTo::TimeStamp {
+            ts: frame as u64,
+            track_id: self.track_id,
+        };
+        match self.reader.seek(SeekMode::Accurate, to) {
+            Ok(seeked) => {
+                self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+            }
+            Err(e) => eprintln!("seeking to frame {}: {}", frame, e),
+        }
+        self.decoder.reset();
+    }
+
+    fn read(&mut self, n: usize, too_loud: &mut bool) -> Vec<f32> {
+        while self.pending.len() < n {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => break,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    eprintln!("decoding packet: {}", e);
+                    continue;
+                }
+            };
+            let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            buf.copy_interleaved_ref(decoded);
+            let n_frames = (buf.len() / self.n_channels) as u64;
+            let skip = std::cmp::min(self.skip, n_frames);
+            self.skip -= skip;
+            let samples = &buf.samples()[skip as usize * self.n_channels..];
+            self.pending.extend_from_slice(samples);
+        }
+        let n = std::cmp::min(n, self.pending.len());
+        let full_scale = match self.kind {
+            SampleKind::Int(bits) => 1.0 - 1.0 / (1_i64 << (bits - 1)) as f32,
+            SampleKind::Float => 1.0,
+        };
+        let samples: Vec<f32> = self.pending.drain(..n).collect();
+        if samples.iter().any(|s| *s >= full_scale || *s <= -1.0) {
+            *too_loud = true;
+        }
+        samples
+    }
+}
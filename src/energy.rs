@@ -0,0 +1,141 @@
+use rand::Rng;
+use rand_distr::{Distribution, WeightedIndex};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::wav::WavDesc;
+
+pub const DEFAULT_SILENCE_DB: f32 = -50.0;
+// Resolution of the energy map, in whole 100 ms loudness steps.
+pub const STEPS_PER_ENERGY_STEP: usize = 2;
+// Avoiding silence gives up after this many quiet landings in a row.
+const MAX_PLACEMENT_TRIES: u32 = 16;
+
+// How grains are placed within a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    Uniform,
+    Avoid,  // retoss grains landing on mostly silence
+    Weight, // favor loud stretches in proportion to their power
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uniform" => Ok(Placement::Uniform),
+            "avoid" => Ok(Placement::Avoid),
+            "weight" => Ok(Placement::Weight),
+            _ => Err(format!(
+                "placement {:?} is not one of uniform, avoid or weight",
+                s
+            )),
+        }
+    }
+}
+
+// K-weighted level over the length of a file, in whole dB per step.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EnergyMap {
+    pub step_ms: u32,
+    pub db: Vec<i8>,
+}
+
+impl EnergyMap {
+    pub fn from_db(step_ms: u32, db: impl Iterator<Item = f64>) -> Self {
+        EnergyMap {
+            step_ms,
+            db: db
+                .map(|db| db.round().clamp(i8::MIN as f64, i8::MAX as f64) as i8)
+                .collect(),
+        }
+    }
+
+    fn step_frames(&self, sample_rate: u32) -> u32 {
+        std::cmp::max(1, sample_rate * self.step_ms / 1000)
+    }
+
+    // The level of frames start..start + len, averaging power.
+    fn level_db(&self, start: u32, len: u32, sample_rate: u32) -> f32 {
+        let step_frames = self.step_frames(sample_rate);
+        let first = (start / step_frames) as usize;
+        let last = ((start + len) / step_frames) as usize;
+        let steps = match self.db.get(first..std::cmp::min(last + 1, self.db.len())) {
+            Some(steps) if !steps.is_empty() => steps,
+            _ => return 0.0, // unmapped, so never taken for silence
+        };
+        let power: f32 = steps.iter().map(|db| power(*db)).sum::<f32>() / steps.len() as f32;
+        10.0 * power.log10()
+    }
+}
+
+fn power(db: i8) -> f32 {
+    10.0_f32.powf(db as f32 / 10.0)
+}
+
+// Chooses grain starts within one file by its energy map.
+pub struct Placer {
+    placement: Placement,
+    silence_db: f32,
+    energy: Arc<EnergyMap>,
+    sample_rate: u32,
+    step_frames: u32,
+    weights: Option<WeightedIndex<f32>>,
+}
+
+impl Placer {
+    pub fn new(placement: Placement, silence_db: f32, wav: &WavDesc) -> Self {
+        let sample_rate = wav.spec.sample_rate;
+        let weights = match placement {
+            Placement::Weight => {
+                let weights = wav.energy.db.iter().map(|db| {
+                    if (*db as f32) < silence_db {
+                        0.0
+                    } else {
+                        power(*db)
+                    }
+                });
+                // An entirely quiet file falls back to uniform placement.
+                WeightedIndex::new(weights).ok()
+            }
+            _ => None,
+        };
+        Placer {
+            placement,
+            silence_db,
+            energy: wav.energy.clone(),
+            sample_rate,
+            step_frames: wav.energy.step_frames(sample_rate),
+            weights,
+        }
+    }
+
+    // A start for a grain of len frames in a file of n frames.
+    pub fn start<R: Rng>(&self, n: u32, len: u32, rng: &mut R) -> u32 {
+        let rounding_error = 1; // one-sample safety margin
+        let uniform = rand_distr::Uniform::from(0..n - rounding_error - len);
+        match (self.placement, &self.weights) {
+            (Placement::Avoid, _) => {
+                let mut start = uniform.sample(rng);
+                for _ in 1..MAX_PLACEMENT_TRIES {
+                    if self.energy.level_db(start, len, self.sample_rate) >= self.silence_db {
+                        break;
+                    }
+                    start = uniform.sample(rng);
+                }
+                start
+            }
+            (Placement::Weight, Some(weights)) => {
+                let step = weights.sample(rng) as u32;
+                let offset = rand_distr::Uniform::from(0..self.step_frames).sample(rng);
+                std::cmp::min(
+                    step * self.step_frames + offset,
+                    n - rounding_error - len - 1,
+                )
+            }
+            _ => uniform.sample(rng),
+        }
+    }
+}
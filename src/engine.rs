@@ -0,0 +1,555 @@
+use crossbeam_channel::{bounded, Receiver, RecvError, Sender};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{io, path, thread};
+use walkdir::WalkDir;
+
+use crate::catalog::Catalog;
+use crate::config::{Layer, PlayConfig};
+use crate::events::{self, GrainEvent};
+use crate::grain::{self, Chunk, GrainConfig, LayerConfig};
+use crate::master::Master;
+use crate::params::Params;
+use crate::wav::{self, ChannelPolicy, WavDesc};
+
+pub const DEFAULT_SAMPLE_RATE: usize = 44100;
+// Grain lengths are whole frames per millisecond.
+pub const MIN_SAMPLE_RATE: usize = 1000;
+pub const DEFAULT_N_PRODUCERS: u32 = 10;
+pub const DEFAULT_FADE_IN_MS: u32 = 3000;
+pub const DEFAULT_FADE_OUT_MS: u32 = 3000;
+pub const DEFAULT_CEILING_DB: f32 = -1.0;
+
+// Builds an Engine from the same settings the play subcommand takes.
+pub struct EngineBuilder {
+    cfg: PlayConfig,
+    sample_rate: usize,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        EngineBuilder {
+            cfg: PlayConfig::default(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_config(cfg: PlayConfig) -> Self {
+        EngineBuilder {
+            cfg,
+            ..Self::default()
+        }
+    }
+
+    pub fn dir(mut self, dir: &str) -> Self {
+        self.cfg.dirs.push(dir.to_string());
+        self
+    }
+
+    pub fn exclude(mut self, wav: path::PathBuf) -> Self {
+        self.cfg.excluded_wavs.insert(wav);
+        self
+    }
+
+    pub fn cap_ms(mut self, cap_ms: u32) -> Self {
+        self.cfg.cap_ms = Some(cap_ms);
+        self
+    }
+
+    pub fn grain_ms(mut self, grain_ms: u32) -> Self {
+        self.cfg.grain_ms = Some(grain_ms);
+        self
+    }
+
+    // Start grains at this many per second across all streams,
+    // instead of each stream playing its grains back to back.
+    pub fn density(mut self, density: f32) -> Self {
+        self.cfg.density = Some(density);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.cfg.seed = Some(seed);
+        self
+    }
+
+    pub fn channel_policy(mut self, policy: ChannelPolicy) -> Self {
+        self.cfg.channel_policy = policy;
+        self
+    }
+
+    pub fn dir_channel_policy(mut self, dir: path::PathBuf, policy: ChannelPolicy) -> Self {
+        self.cfg.dir_channel_policies.push((dir, policy));
+        self
+    }
+
+    // Where survey results are cached between runs, or None for no cache.
+    pub fn catalog(mut self, catalog: Option<path::PathBuf>) -> Self {
+        self.cfg.catalog = catalog;
+        self
+    }
+
+    // Look for added and removed files every so many seconds while running.
+    pub fn rescan_secs(mut self, secs: u32) -> Self {
+        self.cfg.rescan_secs = Some(secs);
+        self
+    }
+
+    // Report each grain as an OSC message to this UDP address.
+    pub fn events_osc(mut self, addr: SocketAddr) -> Self {
+        self.cfg.events_osc = Some(addr);
+        self
+    }
+
+    // Report each grain as a JSON line appended to a file, or to unix:PATH.
+    pub fn events_json(mut self, target: &str) -> Self {
+        self.cfg.events_json = Some(target.to_string());
+        self
+    }
+
+    // Master gain at startup, which may change at runtime.
+    pub fn gain_db(mut self, gain_db: f32) -> Self {
+        self.cfg.gain_db = gain_db;
+        self
+    }
+
+    // How long the stream takes to fade in from silence at startup.
+    pub fn fade_in_ms(mut self, fade_in_ms: u32) -> Self {
+        self.cfg.fade_in_ms = fade_in_ms;
+        self
+    }
+
+    // How long the stream takes to fade out once asked to stop.
+    pub fn fade_out_ms(mut self, fade_out_ms: u32) -> Self {
+        self.cfg.fade_out_ms = fade_out_ms;
+        self
+    }
+
+    // The limiter keeps the output peaks at or below this level in dBFS.
+    pub fn ceiling_db(mut self, ceiling_db: f32) -> Self {
+        self.cfg.ceiling_db = ceiling_db;
+        self
+    }
+
+    pub fn n_producers(mut self, n_producers: u32) -> Self {
+        self.cfg.n_producers = n_producers;
+        self
+    }
+
+    pub fn grain_config(mut self, grain: GrainConfig) -> Self {
+        self.cfg.grain = grain;
+        self
+    }
+
+    // Add a layer of grain streams with their own settings.
+    // With layers, the number of grain streams is the layers' total.
+    pub fn layer(mut self, layer: Layer) -> Self {
+        self.cfg.layers.push(layer);
+        self.cfg.grain.n_grains = self.cfg.layers.iter().map(|l| l.n_grains).sum();
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: usize) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    // Start surveying and grain making in the background.
+    // Fails only when the grain event output cannot be opened.
+    pub fn build(self) -> io::Result<Engine> {
+        let cfg = self.cfg;
+        let sample_rate = self.sample_rate;
+        let (samples_tx, samples_rx) = bounded(2);
+        let (wdescs_tx, wdescs_rx) = bounded(0); // wav description channel
+        let (rescans_tx, rescans_rx) = bounded(0);
+
+        // The RNG hierarchy is derived in a fixed order for reproducible runs.
+        let mut rng = seeded_rng(cfg.seed);
+        let picker_rngs = derive_rngs(&mut rng, cfg.grain.n_grains);
+        let grain_rngs = derive_rngs(&mut rng, cfg.grain.n_grains);
+        let params = Arc::new(Params::new(&cfg));
+        let engine_params = params.clone();
+        let master = Master::new(sample_rate, params.gain(), cfg.fade_in_ms, cfg.ceiling_db);
+        let walker_params = params.clone();
+        let grain_cfg = cfg.grain;
+        let layers: Vec<LayerConfig> = (0..cfg.grain.n_grains)
+            .map(|i| cfg.layer_config(i))
+            .collect();
+        let n_producers = cfg.n_producers;
+        let played = Arc::new(AtomicU64::new(0));
+        let events_tx = if cfg.events_osc.is_some() || cfg.events_json.is_some() {
+            let events_tx =
+                events::start(cfg.events_osc, cfg.events_json.as_deref(), played.clone())?;
+            Some(events_tx)
+        } else {
+            None
+        };
+        thread::Builder::new()
+            .name("wav user".to_string())
+            .spawn(move || {
+                let wavpick_rxs = wav::start_wav_picker(
+                    n_producers,
+                    picker_rngs,
+                    wdescs_rx,
+                    rescans_rx,
+                    params.clone(),
+                );
+                generate_samples(
+                    params,
+                    grain_cfg,
+                    layers,
+                    samples_tx,
+                    sample_rate,
+                    wavpick_rxs,
+                    grain_rngs,
+                );
+            })
+            .expect("spawning wav user");
+        thread::Builder::new()
+            .name("wav walker".to_string())
+            .spawn(move || {
+                survey(cfg.clone(), &walker_params, wdescs_tx);
+                if let Some(secs) = cfg.rescan_secs {
+                    rescan(cfg, &walker_params, secs, rescans_tx);
+                }
+            })
+            .expect("spawning wav walker");
+
+        Ok(Engine {
+            sample_rate,
+            params: engine_params,
+            samples_rx,
+            samples: Vec::new(),
+            events: Vec::new(),
+            consumed: 0,
+            chunk_start: 0,
+            events_tx,
+            played,
+            played_elsewhere: false,
+            master,
+        })
+    }
+}
+
+// A granular walk over WAV directories, pulled as interleaved stereo.
+pub struct Engine {
+    sample_rate: usize,
+    params: Arc<Params>,
+    samples_rx: Receiver<Chunk>,
+    samples: Vec<f32>,
+    events: Vec<GrainEvent>, // latest last
+    consumed: usize,
+    chunk_start: u64, // stream frame where the current chunk begins
+    events_tx: Option<Sender<GrainEvent>>,
+    // Stream frames heard so far, which events wait for.
+    // Filled frames count as heard unless an output keeps the clock.
+    played: Arc<AtomicU64>,
+    played_elsewhere: bool,
+    master: Master,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::new()
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    // Hand the played clock to an output that buffers what it pulls,
+    // which must add each frame to it as the frame is heard.
+    pub fn played_clock(&mut self) -> Arc<AtomicU64> {
+        self.played_elsewhere = true;
+        self.played.clone()
+    }
+
+    // Live settings, shared with whatever controls the engine at runtime.
+    pub fn params(&self) -> Arc<Params> {
+        self.params.clone()
+    }
+
+    // Fill buf with interleaved stereo samples, waiting for grains as needed.
+    // Returns how many samples were written,
+    // which is less than buf.len() only once the stream has ended.
+    // After Params::stop, the stream fades out and ends.
+    pub fn fill(&mut self, buf: &mut [f32]) -> usize {
+        if self.params.stopping() {
+            // Before the first grains there is nothing to fade.
+            let fade_out_ms = if self.samples.is_empty() {
+                0
+            } else {
+                self.params.fade_out_ms()
+            };
+            self.master.fade_out(fade_out_ms);
+        }
+        let mut n_filled = 0;
+        while n_filled < buf.len() {
+            if self.master.faded_out() {
+                println!("engine has faded out");
+                break;
+            }
+            if self.consumed == self.samples.len() {
+                match self.samples_rx.recv() {
+                    Ok(chunk) => {
+                        self.chunk_start += (self.samples.len() / 2) as u64;
+                        self.samples = chunk.samples;
+                        self.events = chunk.events;
+                        self.events.reverse();
+                        self.consumed = 0;
+                    }
+                    Err(RecvError) => {
+                        println!("engine received EOF from samples channel");
+                        break;
+                    }
+                }
+            }
+            let n = std::cmp::min(buf.len() - n_filled, self.samples.len() - self.consumed);
+            let out = &mut buf[n_filled..(n_filled + n)];
+            out.copy_from_slice(&self.samples[self.consumed..(self.consumed + n)]);
+            let n = self.master.process(self.params.gain(), out);
+            self.consumed += n;
+            n_filled += n;
+            self.send_events();
+        }
+        n_filled
+    }
+
+    // Report grains that have started sounding by now.
+    fn send_events(&mut self) {
+        let played = self.consumed / 2;
+        if !self.played_elsewhere {
+            self.played
+                .store(self.chunk_start + played as u64, Ordering::Relaxed);
+        }
+        while self.events.last().is_some_and(|e| e.frame < played) {
+            let mut event = self.events.pop().unwrap();
+            let frame = self.chunk_start + event.frame as u64;
+            event.time = frame as f64 / self.sample_rate as f64;
+            event.frame = frame as usize;
+            if let Some(events_tx) = &self.events_tx {
+                // Never block playback on a slow consumer.
+                let _ = events_tx.try_send(event);
+            }
+        }
+    }
+}
+
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+fn derive_rngs(rng: &mut StdRng, n: u32) -> Vec<StdRng> {
+    (0..n)
+        .map(|_| StdRng::from_rng(&mut *rng).expect("deriving RNG"))
+        .collect()
+}
+
+fn mix(bufs: Vec<Vec<f32>>) -> Vec<f32> {
+    let n = bufs.len();
+    assert_ne!(n, 0);
+    let len = bufs[0].len();
+    let mut mixbuf: Vec<f32> = Vec::new();
+    for i in 0..len {
+        let s: f32 = bufs.iter().map(|buf: &Vec<f32>| buf[i]).sum();
+        mixbuf.push(s / n as f32);
+    }
+    mixbuf
+}
+
+fn generate_samples(
+    params: Arc<Params>,
+    grain_cfg: GrainConfig,
+    layers: Vec<LayerConfig>,
+    samples_tx: Sender<Chunk>,
+    sink_sr: usize,
+    wavpick_rxs: Vec<Receiver<WavDesc>>,
+    grain_rngs: Vec<StdRng>,
+) -> u32 {
+    let mut grains_rxs: Vec<Receiver<Chunk>> = Vec::new();
+    for (i, (wavpick_rx, rng)) in wavpick_rxs.into_iter().zip(grain_rngs).enumerate() {
+        let (grains_tx, grains_rx) = bounded(0);
+        grain::make_grains(
+            i as u32,
+            params.clone(),
+            GrainConfig {
+                layer: layers[i],
+                ..grain_cfg
+            },
+            wavpick_rx,
+            grains_tx,
+            sink_sr,
+            rng,
+        );
+        grains_rxs.push(grains_rx);
+    }
+    let n_grains = grain_cfg.n_grains;
+    // now each grain maker will send JACK-ready samples in chunks mixed below
+
+    thread::Builder::new()
+        .name("mix sender".to_string())
+        .spawn(move || {
+            let mut live = vec![true; grains_rxs.len()];
+            while live[..params.n_grains() as usize].iter().any(|l| *l) {
+                let mut bufs: Vec<Vec<f32>> = Vec::new();
+                let mut events: Vec<GrainEvent> = Vec::new();
+                // Idle grain makers wait on their sends until mixed again.
+                for i in 0..params.n_grains() as usize {
+                    if !live[i] {
+                        continue;
+                    }
+                    match grains_rxs[i].recv() {
+                        Ok(chunk) => {
+                            bufs.push(chunk.samples);
+                            events.extend(chunk.events);
+                        }
+                        Err(RecvError) => {
+                            live[i] = false;
+                            println!(
+                                "Grain buffer receiver got RecvError -> {} grain makers remaining",
+                                live.iter().filter(|l| **l).count()
+                            );
+                        }
+                    }
+                }
+                if !bufs.is_empty() {
+                    let mixed = mix(bufs);
+                    println!(
+                        "generate_samples sending {} mixed stereo samples",
+                        mixed.len() / 2
+                    );
+                    events.sort_by_key(|e| e.frame);
+                    let chunk = Chunk {
+                        samples: mixed,
+                        events,
+                    };
+                    if samples_tx.send(chunk).is_err() {
+                        println!("generate_samples found the engine gone");
+                        return;
+                    }
+                } else {
+                    println!("generate_samples without anything to send");
+                }
+            }
+        })
+        .expect("spawning mix sender");
+
+    n_grains
+}
+
+// Describe every WAV under the configured directories,
+// sending one None per surveyor when done.
+// Walking stops early once the engine is stopping.
+pub(crate) fn survey(cfg: PlayConfig, params: &Params, wdescs_tx: Sender<Option<WavDesc>>) {
+    let catalog = match &cfg.catalog {
+        Some(p) => Catalog::load(p),
+        None => Catalog::default(),
+    };
+    println!("catalog has {} entries", catalog.len());
+    let catalog = Arc::new(Mutex::new(catalog));
+    let (done_tx, done_rx) = bounded(0); // worker completion channel
+    {
+        // At the end of this scope, dirs_tx dropped - we're done sending directories.
+        let (dirs_tx, dirs_rx) = bounded(0);
+        for w in 0..cfg.n_producers {
+            let dirs_rx = dirs_rx.clone();
+            let done_tx = done_tx.clone();
+            let wdescs_tx = wdescs_tx.clone();
+            let cfg = cfg.clone();
+            let catalog = catalog.clone();
+            thread::Builder::new()
+                .name("wav surveyor".to_string())
+                .spawn(move || {
+                    wav::survey_wavs(w, cfg, catalog, dirs_rx, wdescs_tx, done_tx);
+                })
+                .expect("spawning wav surveyor");
+        }
+        'walk: for d in cfg.dirs.iter() {
+            for entry in WalkDir::new(d).into_iter().filter_map(|e| e.ok()) {
+                if params.stopping() {
+                    println!("survey stopping early");
+                    break 'walk;
+                }
+                if !cfg.excluded_wavs.contains(entry.path()) {
+                    let p = path::PathBuf::from(entry.path());
+                    dirs_tx.send(p).unwrap();
+                }
+            }
+        }
+    }
+    let mut n_workers = cfg.n_producers;
+    while n_workers > 0 {
+        match done_rx.recv() {
+            Ok(worker_id) => {
+                println!("worker {} finished", worker_id);
+                n_workers -= 1;
+            }
+            Err(RecvError) => {
+                println!("work generator done channel was closed");
+            }
+        }
+    }
+    let mut catalog = catalog.lock().unwrap();
+    catalog.prune(&cfg.dirs);
+    if let Some(p) = &cfg.catalog {
+        if catalog.is_dirty() {
+            match catalog.save(p) {
+                Ok(()) => println!("saved {} catalog entries to {:?}", catalog.len(), p),
+                Err(e) => eprintln!("saving catalog {:?}: {}", p, e),
+            }
+        }
+    }
+}
+
+// Survey the configured directories and gather the descriptions.
+pub(crate) fn survey_all(cfg: PlayConfig, params: &Params) -> Vec<WavDesc> {
+    let (wdescs_tx, wdescs_rx) = bounded(0);
+    let n_producers = cfg.n_producers;
+    let collector = thread::Builder::new()
+        .name("wav collector".to_string())
+        .spawn(move || {
+            let mut wavs: Vec<WavDesc> = Vec::new();
+            let mut n_done = 0;
+            while n_done < n_producers {
+                match wdescs_rx.recv() {
+                    Ok(Some(wdesc)) => wavs.push(wdesc),
+                    Ok(None) | Err(_) => n_done += 1,
+                }
+            }
+            wavs
+        })
+        .expect("spawning wav collector");
+    survey(cfg, params, wdescs_tx);
+    collector.join().expect("joining wav collector")
+}
+
+// Periodically hand the picker a fresh survey until it stops listening.
+// The catalog keeps rescans cheap for files that have not changed.
+fn rescan(cfg: PlayConfig, params: &Params, secs: u32, rescans_tx: Sender<Vec<WavDesc>>) {
+    loop {
+        thread::sleep(Duration::from_secs(secs as u64));
+        if params.stopping() {
+            println!("rescan stopping");
+            return;
+        }
+        let wavs = survey_all(cfg.clone(), params);
+        println!("rescan found {} wav descriptions", wavs.len());
+        if rescans_tx.send(wavs).is_err() {
+            println!("rescan found the wav picker gone");
+            return;
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crossbeam_channel::{bounded, Sender};
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::Serialize;
+use std::io::prelude::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, io, path, thread};
+
+// Events waiting for a slow consumer are dropped beyond this many.
+const EVENT_QUEUE_LEN: usize = 1024;
+// How often a waiting event checks whether its grain is heard yet.
+const CLOCK_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+// One grain, reported when it starts sounding in the output stream.
+#[derive(Clone, Debug, Serialize)]
+pub struct GrainEvent {
+    pub time: f64,   // stream seconds
+    pub stream: u32, // which grain maker
+    pub path: path::PathBuf,
+    pub offset: f64, // seconds into the source
+    pub length: f64, // seconds
+    pub gain: f32,
+    pub pan: f32,  // -1 left to 1 right
+    pub rate: f32, // playback rate, 2 for an octave up
+    pub reversed: bool,
+    pub width: f32, // 0 for mono to 1 for the recorded image
+    // sample frame within the chunk carrying the grain,
+    // then within the whole stream once the engine plays it
+    #[serde(skip)]
+    pub(crate) frame: usize,
+}
+
+// Start writing events as OSC messages and/or JSON lines.
+// The JSON target is a file to append to, or unix:PATH for a listening socket.
+// Each event waits until the played clock, in stream frames, reaches its grain,
+// so audio buffered on its way to the speakers does not put events early.
+pub fn start(
+    osc: Option<SocketAddr>,
+    json: Option<&str>,
+    played: Arc<AtomicU64>,
+) -> io::Result<Sender<GrainEvent>> {
+    let osc = match osc {
+        Some(addr) => {
+            let local: SocketAddr = if addr.is_ipv4() {
+                "0.0.0.0:0".parse().unwrap()
+            } else {
+                "[::]:0".parse().unwrap()
+            };
+            let socket = UdpSocket::bind(local)
+                .map_err(|e| io::Error::new(e.kind(), format!("events to {}: {}", addr, e)))?;
+            Some((socket, addr))
+        }
+        None => None,
+    };
+    let mut json: Option<Box<dyn Write + Send>> =
+        match json {
+            Some(target) => {
+                let opened: io::Result<Box<dyn Write + Send>> = match target.strip_prefix("unix:") {
+                    Some(socket) => UnixStream::connect(socket).map(|s| Box::new(s) as _),
+                    None => fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(target)
+                        .map(|f| Box::new(f) as _),
+                };
+                Some(opened.map_err(|e| {
+                    io::Error::new(e.kind(), format!("events to {}: {}", target, e))
+                })?)
+            }
+            None => None,
+        };
+    let (events_tx, events_rx) = bounded::<GrainEvent>(EVENT_QUEUE_LEN);
+    thread::Builder::new()
+        .name("event writer".to_string())
+        .spawn(move || {
+            for event in events_rx {
+                while played.load(Ordering::Relaxed) <= event.frame as u64 {
+                    thread::sleep(CLOCK_POLL_INTERVAL);
+                }
+                if let Some((socket, addr)) = &osc {
+                    let packet = OscPacket::Message(OscMessage {
+                        addr: "/acouwalk/grain".to_string(),
+                        args: vec![
+                            OscType::Float(event.time as f32),
+                            OscType::Int(event.stream as i32),
+                            OscType::String(event.path.to_string_lossy().into_owned()),
+                            OscType::Float(event.offset as f32),
+                            OscType::Float(event.length as f32),
+                            OscType::Float(event.gain),
+                            OscType::Float(event.pan),
+                            OscType::Float(event.rate),
+                            OscType::Bool(event.reversed),
+                            OscType::Float(event.width),
+                        ],
+                    });
+                    let buf = rosc::encoder::encode(&packet).expect("encoding OSC event");
+                    if let Err(e) = socket.send_to(&buf, addr) {
+                        eprintln!("sending OSC event to {}: {}", addr, e);
+                    }
+                }
+                if let Some(out) = &mut json {
+                    let line = serde_json::to_string(&event).expect("encoding JSON event");
+                    if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+                        eprintln!("writing JSON events stopped: {}", e);
+                        json = None;
+                    }
+                }
+            }
+        })?;
+    Ok(events_tx)
+}
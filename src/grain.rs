@@ -1,82 +1,206 @@
 use crossbeam_channel::{Receiver, Sender};
+use rand::rngs::StdRng;
+use rand::Rng;
 use rand_distr::Distribution;
 use samplerate::{convert, ConverterType};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::thread;
 
+use crate::decode;
+use crate::energy::{Placement, Placer, DEFAULT_SILENCE_DB};
+use crate::events::GrainEvent;
+use crate::pan::{self, Motion};
+use crate::params::Params;
+use crate::pitch::Pitch;
+use crate::schedule::Spacing;
 use crate::wav::WavDesc;
+use crate::window::{Envelope, WindowShape};
 
-const DEFAULT_TUKEY_WINDOW_ALPHA: f32 = 0.5;
-pub const N_GRAINS: u32 = 5;
+pub const DEFAULT_TUKEY_WINDOW_ALPHA: f32 = 0.5;
+pub const DEFAULT_N_GRAINS: u32 = 5;
 const GRAIN_MS: u32 = 1000;
-const GRAIN_BUF_N_SAMPLES: usize = 1024 * 1024;
-const MIN_GRAIN_SIZE_FRACTION: f32 = 0.6;
-const WAV_MAX_TTL: u32 = 10;
+// An hour, long enough for any grain and short enough to count in frames.
+pub const MAX_GRAIN_MS: u32 = 3_600_000;
+pub const DEFAULT_GRAIN_BUF_N_SAMPLES: usize = 1024 * 1024;
+pub const DEFAULT_MIN_GRAIN_SIZE_FRACTION: f32 = 0.6;
+pub const DEFAULT_WAV_MAX_TTL: u32 = 10;
+// Attenuated clipping grains sit 12 dB down.
+const CLIP_ATTENUATION: f32 = 0.25;
+// Retossing gives up and mutes after this many clipping grains in a row.
+const MAX_RETOSSES: u32 = 8;
+
+// What to do with a grain whose source reaches full scale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipPolicy {
+    Mute,
+    Attenuate,
+    Retoss,
+}
+
+impl FromStr for ClipPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mute" => Ok(ClipPolicy::Mute),
+            "attenuate" => Ok(ClipPolicy::Attenuate),
+            "retoss" => Ok(ClipPolicy::Retoss),
+            _ => Err(format!(
+                "clip policy {:?} is not one of mute, attenuate or retoss",
+                s
+            )),
+        }
+    }
+}
+
+// Tunables shared by every grain maker.
+#[derive(Clone, Copy, Debug)]
+pub struct GrainConfig {
+    pub n_grains: u32,
+    pub wav_max_ttl: u32,
+    pub min_grain_size_fraction: f32,
+    // run-wide, until a grain maker is given its own layer's
+    pub layer: LayerConfig,
+    pub buf_n_samples: usize,
+    pub clip_policy: ClipPolicy,
+    // Gain-match each file to this integrated loudness, if given.
+    pub target_lufs: Option<f32>,
+    pub placement: Placement,
+    pub silence_db: f32,
+    // How onsets are spaced when a density is set.
+    pub spacing: Spacing,
+}
+
+impl Default for GrainConfig {
+    fn default() -> Self {
+        GrainConfig {
+            n_grains: DEFAULT_N_GRAINS,
+            wav_max_ttl: DEFAULT_WAV_MAX_TTL,
+            min_grain_size_fraction: DEFAULT_MIN_GRAIN_SIZE_FRACTION,
+            layer: LayerConfig::default(),
+            buf_n_samples: DEFAULT_GRAIN_BUF_N_SAMPLES,
+            clip_policy: ClipPolicy::Attenuate,
+            target_lufs: None,
+            placement: Placement::Uniform,
+            silence_db: DEFAULT_SILENCE_DB,
+            spacing: Spacing::Poisson,
+        }
+    }
+}
+
+// Settings that may differ between layers of grain streams.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerConfig {
+    pub window: WindowShape,
+    pub pitch: Pitch,
+    // the chance of each grain playing backwards
+    pub reverse_prob: f32,
+    pub pan: Motion,   // from -1 left to 1 right
+    pub width: Motion, // from 0 for mono to 1 for the recorded image
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        LayerConfig {
+            window: WindowShape::Tukey(DEFAULT_TUKEY_WINDOW_ALPHA),
+            pitch: Pitch::Fixed(0.0),
+            reverse_prob: 0.0,
+            pan: Motion::Fixed(0.0),
+            width: Motion::Fixed(1.0),
+        }
+    }
+}
+
+// Samples from one grain maker with the grains that start in them.
+pub struct Chunk {
+    pub samples: Vec<f32>,
+    pub events: Vec<GrainEvent>,
+}
 
 pub struct Grain {
     start: u32,
     len: u32,
     max_len: u32,
+    min_size_fraction: f32,
 }
 
 impl Grain {
-    pub fn new(grain_ms: Option<u32>, sr: u32) -> Self {
+    pub fn new(grain_ms: Option<u32>, sr: u32, min_size_fraction: f32) -> Self {
         let sr_ms = sr / 1000;
         let len = match grain_ms {
             Some(g) => g,
             None => GRAIN_MS,
-        } * sr_ms;
+        }
+        .saturating_mul(sr_ms);
+        // Even at rates too low for whole frames per millisecond,
+        // a grain has some audio in it.
+        let len = len.max(1);
         Grain {
             start: 0,
             max_len: len,
             len,
+            min_size_fraction,
         }
     }
-    // Toss this grain in the air and let it randomly land somewhere.
-    pub fn toss(&mut self, n: u32) {
-        let mut rng = rand::thread_rng();
-        let g_right = 1.0 - MIN_GRAIN_SIZE_FRACTION;
-        let g_right_fraction = rand_distr::Uniform::from(0.0..1.0).sample(&mut rng);
+
+    // The average length of a tossed grain, in the frames it was made for.
+    pub fn mean_len(&self) -> f32 {
+        self.max_len as f32 * (1.0 + self.min_size_fraction) / 2.0
+    }
+
+    // Toss this grain in the air and let it land where the placer allows.
+    // Grains played at a faster rate take more of the source.
+    pub fn toss<R: Rng>(&mut self, n: u32, rate: f32, placer: &Placer, rng: &mut R) {
+        let g_right = 1.0 - self.min_size_fraction;
+        let g_right_fraction = rand_distr::Uniform::from(0.0..1.0).sample(rng);
         // The random "extra" above-minimum length avoids grain synchronization.
         let g_extra = g_right * g_right_fraction;
-        let g_size = self.max_len as f32 * (MIN_GRAIN_SIZE_FRACTION + g_extra);
-        self.len = g_size as u32;
-        let rounding_error = 1; // one-sample safety margin
-        self.start = rand_distr::Uniform::from(0..n - rounding_error - self.len).sample(&mut rng);
-    }
-    // https://en.wikipedia.org/wiki/Window_function#Tukey_window
-    pub fn amplitude(&self, pos: usize, alpha: Option<f32>) -> f32 {
-        let alpha = match alpha {
-            Some(a) => a,
-            None => DEFAULT_TUKEY_WINDOW_ALPHA,
-        };
-
-        let n = pos as f32;
-        let len = self.len as f32;
-        let n = if n >= (len / 2.0) { len - n } else { n };
-
-        if n < alpha * len / 2.0 {
-            let x = (2.0 * std::f32::consts::PI * n) / (alpha * len);
-            0.5 * (1.0 - x.cos())
-        } else {
-            1.0
-        }
+        let g_size = self.max_len as f32 * (self.min_size_fraction + g_extra);
+        self.len = ((g_size * rate) as u32).min(n.saturating_sub(2));
+        self.start = placer.start(n, self.len, rng);
     }
 }
 
 pub fn make_grains(
     grain_maker_id: u32,
-    grain_ms: Option<u32>,
+    params: Arc<Params>,
+    cfg: GrainConfig,
     wavpick_rx: Receiver<WavDesc>,
-    grains_tx: Sender<Vec<f32>>,
+    grains_tx: Sender<Chunk>,
     sink_sr: usize,
+    mut rng: StdRng,
 ) {
-    let mut g = Grain::new(grain_ms, sink_sr as u32);
+    let mut grain_ms = params.grain_ms();
+    let mut g = Grain::new(grain_ms, sink_sr as u32, cfg.min_grain_size_fraction);
     thread::Builder::new()
         .name("grain maker".to_string())
         .spawn(move || {
-            println!("grain maker {} starting", grain_maker_id);
-            let mut rng = rand::thread_rng();
+            println!(
+                "grain maker {} starting with window {}, pitch {}, reverse probability {}, pan {} and width {}",
+                grain_maker_id,
+                cfg.layer.window,
+                cfg.layer.pitch,
+                cfg.layer.reverse_prob,
+                cfg.layer.pan,
+                cfg.layer.width
+            );
+            let envelope = Envelope::new(cfg.layer.window);
             let mut send_buf: Vec<f32> = Vec::new();
+            let mut events: Vec<GrainEvent> = Vec::new();
+            // The frame in send_buf where the next grain starts.
+            // Grains may overlap it or leave silence before it.
+            let mut onset = match params.density() {
+                Some(density) => {
+                    let rate = density / params.n_grains() as f32;
+                    cfg.spacing.first_onset(rate, sink_sr, &mut rng)
+                }
+                None => 0,
+            };
+            // frames sent in earlier chunks, for sweeping pan and width
+            let mut sent_frames: u64 = 0;
+            // Spread the streams' sweep phases evenly by the golden ratio.
+            let phase = (grain_maker_id as f32 * 0.618_034).fract();
             loop {
                 let wav = match wavpick_rx.recv() {
                     Err(e) => {
@@ -85,57 +209,173 @@ pub fn make_grains(
                     }
                     Ok(w) => w,
                 };
-                let mut r = hound::WavReader::open(wav.path.clone()).ok().unwrap();
-                let src_sr = r.spec().sample_rate;
-                let ttl = rand_distr::Uniform::from(1..WAV_MAX_TTL).sample(&mut rng);
+                // Files can disappear between a rescan and their next pick.
+                let mut r = match decode::open(&wav) {
+                    Some(r) => r,
+                    None => {
+                        eprintln!("grain maker {} cannot open {:?}", grain_maker_id, wav.path);
+                        continue;
+                    }
+                };
+                let src_sr = wav.spec.sample_rate;
+                let wav_gain = match cfg.target_lufs {
+                    Some(target) => wav.loudness.map_or(1.0, |l| l.gain_to(target)),
+                    None => 1.0,
+                };
+                let placer = Placer::new(cfg.placement, cfg.silence_db, &wav);
+                let ttl = rand_distr::Uniform::from(1..cfg.wav_max_ttl).sample(&mut rng);
                 for _ in 0..ttl {
+                    if params.grain_ms() != grain_ms {
+                        grain_ms = params.grain_ms();
+                        g = Grain::new(grain_ms, sink_sr as u32, cfg.min_grain_size_fraction);
+                    }
+                    let n_channels = wav.spec.channels;
                     let mut too_loud = false;
-                    g.toss(wav.n_samples);
-                    r.seek(g.start).ok();
-                    let mut src_samples: Vec<f32> = r
-                        .samples()
-                        .take((g.len * 2) as usize)
-                        .map(|e: Result<i16, hound::Error>| {
-                            let s = e.ok().unwrap();
-                            if s == i16::MAX || s == i16::MIN {
-                                too_loud = true;
-                            }
-                            s as f32 / i16::MAX as f32
-                        })
-                        .enumerate()
-                        .map(|(i, s)| s * g.amplitude(i / 2, None))
-                        .collect();
+                    let mut raw: Vec<f32> = Vec::new();
+                    let rate = cfg.layer.pitch.rate(&mut rng);
+                    for _ in 0..MAX_RETOSSES {
+                        too_loud = false;
+                        g.toss(wav.n_samples, rate, &placer, &mut rng);
+                        r.seek(g.start);
+                        raw = r.read(g.len as usize * n_channels as usize, &mut too_loud);
+                        if !too_loud || cfg.clip_policy != ClipPolicy::Retoss {
+                            break;
+                        }
+                    }
+                    let gain = match (too_loud, cfg.clip_policy) {
+                        (false, _) => wav_gain,
+                        (true, ClipPolicy::Attenuate) => wav_gain * CLIP_ATTENUATION,
+                        (true, _) => 0.0,
+                    };
                     if too_loud {
-                        println!("muting {:?} at too-loud sample index {}", wav.path, g.start);
-                        src_samples = src_samples.iter().map(|_| 0.0).collect();
+                        println!(
+                            "{:?} clips at sample index {}: grain gain {}",
+                            wav.path, g.start, gain
+                        );
                     }
-                    if src_sr == sink_sr as u32 {
-                        send_buf.append(&mut src_samples);
+                    // Grains started per second by this stream, if not back to back.
+                    let stream_rate = params.density().map(|d| d / params.n_grains() as f32);
+                    // Grains overlapping in a dense cloud add up,
+                    // so each is scaled by the square root of the expected overlap
+                    // to keep about the level of grains played back to back.
+                    let gain = match stream_rate {
+                        Some(rate) => {
+                            let overlap = rate * g.mean_len() / sink_sr as f32;
+                            gain / overlap.max(1.0).sqrt()
+                        }
+                        None => gain,
+                    };
+                    let mut stereo = wav.channel_map.to_stereo(n_channels, &raw);
+                    // A file cut short may give fewer frames than the grain asked for.
+                    let n_read = stereo.len() / 2;
+                    // Only draw when reversing is possible, keeping seeded runs as they were.
+                    let reversed =
+                        cfg.layer.reverse_prob > 0.0 && rng.gen::<f32>() < cfg.layer.reverse_prob;
+                    if reversed {
+                        reverse_frames(&mut stereo);
+                    }
+                    let secs = (sent_frames + onset as u64) as f64 / sink_sr as f64;
+                    let grain_pan = cfg.layer.pan.value(secs, phase, &mut rng);
+                    let width = cfg.layer.width.value(secs, phase, &mut rng);
+                    pan::pan_frames(&mut stereo, grain_pan, width);
+                    let src_samples: Vec<f32> = stereo
+                        .iter()
+                        .enumerate()
+                        .map(|(i, s)| s * gain * envelope.amplitude(i / 2, n_read))
+                        .collect();
+                    events.push(GrainEvent {
+                        time: 0.0, // known once the engine plays it
+                        stream: grain_maker_id,
+                        path: wav.path.clone(),
+                        offset: g.start as f64 / src_sr as f64,
+                        length: n_read as f64 / src_sr as f64,
+                        gain,
+                        pan: grain_pan,
+                        rate,
+                        reversed,
+                        width,
+                        frame: onset,
+                    });
+                    // Resampling from a rate scaled by the playback rate
+                    // shifts the pitch along with the speed.
+                    let play_sr = (src_sr as f32 * rate).round() as u32;
+                    let mut sink_samples = if play_sr == sink_sr as u32 {
+                        src_samples
                     } else {
-                        let mut sink_samples = convert(
-                            src_sr,
+                        convert(
+                            play_sr,
                             sink_sr as u32,
                             2,
                             ConverterType::SincBestQuality,
                             &src_samples[..],
                         )
-                        .expect("converting sample rate");
+                        .expect("converting sample rate")
+                    };
+                    let n_frames = sink_samples.len() / 2;
+                    if onset * 2 >= send_buf.len() {
+                        send_buf.resize(onset * 2, 0.0);
                         send_buf.append(&mut sink_samples);
+                    } else {
+                        // Overlapping grains sum.
+                        let end = (onset + n_frames) * 2;
+                        if send_buf.len() < end {
+                            send_buf.resize(end, 0.0);
+                        }
+                        for (out, s) in send_buf[onset * 2..end].iter_mut().zip(sink_samples) {
+                            *out += s;
+                        }
                     }
-                    if send_buf.len() >= GRAIN_BUF_N_SAMPLES {
-                        let send_part: Vec<f32> =
-                            send_buf.iter().take(GRAIN_BUF_N_SAMPLES).copied().collect();
-                        let new_len = send_buf.len() - GRAIN_BUF_N_SAMPLES;
-                        let src_start = GRAIN_BUF_N_SAMPLES;
-                        let src_end = src_start + new_len;
-                        for (i, j) in (src_start..src_end).enumerate() {
-                            send_buf[i] = send_buf[j];
+                    onset += match stream_rate {
+                        Some(rate) => cfg.spacing.interval(rate, sink_sr, &mut rng),
+                        None => n_frames,
+                    };
+                    // A chunk is done once no later grain can start in it.
+                    let chunk_frames = cfg.buf_n_samples / 2;
+                    while onset >= chunk_frames {
+                        if send_buf.len() < cfg.buf_n_samples {
+                            send_buf.resize(cfg.buf_n_samples, 0.0);
+                        }
+                        let send_part: Vec<f32> = send_buf.drain(..cfg.buf_n_samples).collect();
+                        onset -= chunk_frames;
+                        sent_frames += chunk_frames as u64;
+                        // Grains starting past this chunk go out with a later one.
+                        let (send_events, later): (Vec<GrainEvent>, Vec<GrainEvent>) =
+                            events.drain(..).partition(|e| e.frame < chunk_frames);
+                        events = later;
+                        for e in events.iter_mut() {
+                            e.frame -= chunk_frames;
+                        }
+                        let chunk = Chunk {
+                            samples: send_part,
+                            events: send_events,
+                        };
+                        if grains_tx.send(chunk).is_err() {
+                            println!("grain maker {} found the mixer gone", grain_maker_id);
+                            return;
                         }
-                        send_buf.truncate(new_len);
-                        grains_tx.send(send_part).unwrap();
                     }
                 }
             }
         })
         .expect("spawning grain maker");
 }
+
+// Reverse interleaved stereo frame by frame, keeping left on the left.
+fn reverse_frames(stereo: &mut [f32]) {
+    stereo.reverse();
+    for frame in stereo.chunks_exact_mut(2) {
+        frame.swap(0, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversed_frames_keep_their_channels() {
+        let mut stereo = [1.0, 2.0, 3.0, 4.0]; // [L0, R0, L1, R1]
+        reverse_frames(&mut stereo);
+        assert_eq!(stereo, [3.0, 4.0, 1.0, 2.0]);
+    }
+}
@@ -0,0 +1,36 @@
+//! Stereo granular streaming over directories of field recordings.
+//!
+//! An [`Engine`] surveys the audio files under its directories
+//! and mixes windowed grains from them into an endless stream,
+//! which callers pull with [`Engine::fill`].
+//!
+//! ```no_run
+//! let mut engine = acoustic_walk::Engine::builder()
+//!     .dir("recordings")
+//!     .grain_ms(3000)
+//!     .build()
+//!     .expect("opening the grain event output");
+//! let mut buf = vec![0.0; 2 * 1024];
+//! engine.fill(&mut buf);
+//! ```
+
+pub mod catalog;
+pub mod config;
+pub mod control;
+pub mod decode;
+pub mod energy;
+pub mod engine;
+pub mod events;
+pub mod grain;
+pub mod loudness;
+pub mod master;
+pub mod osc;
+pub mod output;
+pub mod pan;
+pub mod params;
+pub mod pitch;
+pub mod schedule;
+pub mod wav;
+pub mod window;
+
+pub use engine::{Engine, EngineBuilder};
@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+use std::path;
+
+use crate::decode::{self, Format, Probe};
+use crate::energy::{EnergyMap, STEPS_PER_ENERGY_STEP};
+use crate::wav::ChannelMap;
+
+// EBU R128 gating blocks are 400 ms, overlapping by 75%.
+const STEP_MS: usize = 100;
+const STEPS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+// Integrated loudness and sample peak of a whole file as it plays in stereo.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Loudness {
+    pub lufs: Option<f32>, // None for files too short or too quiet to gate
+    pub peak: f32,         // linear, from 0 to 1
+}
+
+impl Loudness {
+    // The gain bringing this file to the target loudness,
+    // held down so the file's peak stays under full scale.
+    pub fn gain_to(&self, target_lufs: f32) -> f32 {
+        let lufs = match self.lufs {
+            Some(lufs) => lufs,
+            None => return 1.0,
+        };
+        let gain = 10.0_f32.powf((target_lufs - lufs) / 20.0);
+        if self.peak > 0.0 {
+            gain.min(1.0 / self.peak)
+        } else {
+            gain
+        }
+    }
+}
+
+// Decode a whole file to measure it as ITU-R BS.1770 describes,
+// through the channel map, so the measurement is of the stereo that plays,
+// and map its level over time on the way.
+pub fn measure(
+    path: &path::Path,
+    format: Format,
+    probe: &Probe,
+    channel_map: ChannelMap,
+) -> Option<(Loudness, EnergyMap)> {
+    let mut r = decode::open_file(path, format, probe.kind, probe.spec.channels)?;
+    let n_channels = probe.spec.channels as usize;
+    let mut meter = Meter::new(probe.spec.sample_rate);
+    let mut too_loud = false;
+    loop {
+        let samples = r.read(meter.step_frames * n_channels, &mut too_loud);
+        let stereo = channel_map.to_stereo(probe.spec.channels, &samples);
+        if !meter.push(&stereo) {
+            break;
+        }
+    }
+    Some(meter.finish())
+}
+
+// Loudness of interleaved stereo fed to it one 100 ms step at a time.
+struct Meter {
+    filters: [KWeighting; 2],
+    step_frames: usize,
+    steps: Vec<f64>, // mean square summed over channels
+    peak: f32,
+}
+
+impl Meter {
+    fn new(sample_rate: u32) -> Self {
+        let sr = sample_rate as f64;
+        Meter {
+            filters: [KWeighting::new(sr), KWeighting::new(sr)],
+            step_frames: sample_rate as usize * STEP_MS / 1000,
+            steps: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    // Take one step, returning false for a short final step,
+    // which counts toward the peak only.
+    fn push(&mut self, stereo: &[f32]) -> bool {
+        self.peak = stereo.iter().fold(self.peak, |m, s| m.max(s.abs()));
+        if stereo.len() < self.step_frames * 2 {
+            return false;
+        }
+        let mut sum = 0.0;
+        for frame in stereo.chunks_exact(2) {
+            for (s, filter) in frame.iter().zip(self.filters.iter_mut()) {
+                let y = filter.process(*s as f64);
+                sum += y * y;
+            }
+        }
+        self.steps.push(sum / self.step_frames as f64);
+        true
+    }
+
+    fn finish(self) -> (Loudness, EnergyMap) {
+        let steps = self.steps;
+        let peak = self.peak;
+        let energy = EnergyMap::from_db(
+            (STEP_MS * STEPS_PER_ENERGY_STEP) as u32,
+            steps
+                .chunks(STEPS_PER_ENERGY_STEP)
+                .map(|c| lufs(c.iter().sum::<f64>() / c.len() as f64)),
+        );
+        let blocks: Vec<f64> = steps
+            .windows(STEPS_PER_BLOCK)
+            .map(|w| w.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+            .filter(|power| lufs(*power) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if blocks.is_empty() {
+            return (Loudness { lufs: None, peak }, energy);
+        }
+        let relative_gate = lufs(mean(&blocks)) + RELATIVE_GATE_LU;
+        let gated: Vec<f64> = blocks
+            .into_iter()
+            .filter(|power| lufs(*power) > relative_gate)
+            .collect();
+        let loudness = Loudness {
+            lufs: Some(lufs(mean(&gated)) as f32),
+            peak,
+        };
+        (loudness, energy)
+    }
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn mean(powers: &[f64]) -> f64 {
+    powers.iter().sum::<f64>() / powers.len() as f64
+}
+
+// The BS.1770 pre-filter and high-pass, designed for any sample rate
+// the same way libebur128 does.
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> Self {
+        let pi = std::f64::consts::PI;
+
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (pi * f0 / sample_rate).tan();
+        let vh = 10.0_f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (pi * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting {
+            stages: [shelf, high_pass],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.stages.iter_mut().fold(x, |x, stage| stage.process(x))
+    }
+}
+
+// Direct form II transposed, with a0 normalized to one.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    // Interleaved frames of a 1 kHz sine at level dBFS on every channel.
+    fn sine(level_db: f32, secs: f32, n_channels: usize) -> Vec<f32> {
+        let amplitude = 10.0_f32.powf(level_db / 20.0);
+        let n = (secs * SAMPLE_RATE as f32) as usize;
+        (0..n)
+            .flat_map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let s = amplitude * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+                std::iter::repeat_n(s, n_channels)
+            })
+            .collect()
+    }
+
+    fn measure_played(samples: &[f32], n_channels: u16, channel_map: ChannelMap) -> Loudness {
+        let mut meter = Meter::new(SAMPLE_RATE);
+        let step = meter.step_frames * n_channels as usize;
+        for chunk in samples.chunks(step) {
+            if !meter.push(&channel_map.to_stereo(n_channels, chunk)) {
+                break;
+            }
+        }
+        meter.finish().0
+    }
+
+    // EBU Tech 3341 reads a stereo 1 kHz sine as loud as its level in dBFS.
+    #[test]
+    fn stereo_sine_reads_its_level() {
+        let l = measure_played(&sine(-20.0, 10.0, 2), 2, ChannelMap::Pair(0, 1));
+        assert!((l.lufs.unwrap() + 20.0).abs() < 0.1, "{:?}", l);
+        assert!((20.0 * l.peak.log10() + 20.0).abs() < 0.1, "{:?}", l);
+    }
+
+    // Measurements follow the channel map:
+    // mono sits in the center at equal power, 3 dB under both channels at full level,
+    // and a downmix averages channels to the same level as each.
+    #[test]
+    fn channel_maps_measure_the_played_stereo() {
+        for (n_channels, channel_map, lufs) in [
+            (1, ChannelMap::Mono, -23.0),
+            (4, ChannelMap::Downmix, -20.0),
+            (4, ChannelMap::Pair(2, 3), -20.0),
+        ] {
+            let l = measure_played(
+                &sine(-20.0, 10.0, n_channels),
+                n_channels as u16,
+                channel_map,
+            );
+            assert!(
+                (l.lufs.unwrap() - lufs).abs() < 0.1,
+                "{:?}: {:?}",
+                channel_map,
+                l
+            );
+        }
+    }
+
+    // Quiet passages more than 10 LU down are gated out of the integration.
+    #[test]
+    fn relative_gate_drops_quiet_passages() {
+        let mut samples = sine(-20.0, 10.0, 2);
+        samples.extend(sine(-36.0, 10.0, 2));
+        let l = measure_played(&samples, 2, ChannelMap::Pair(0, 1));
+        assert!((l.lufs.unwrap() + 20.0).abs() < 0.1, "{:?}", l);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let l = measure_played(
+            &vec![0.0; SAMPLE_RATE as usize * 4],
+            2,
+            ChannelMap::Pair(0, 1),
+        );
+        assert!(l.lufs.is_none());
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+
+// Gain changes are smoothed with this time constant to avoid zipper noise.
+const GAIN_SMOOTHING_MS: f32 = 20.0;
+// The limiter sees peaks this far ahead of the output.
+const LIMITER_LOOKAHEAD_MS: usize = 5;
+// Gain reduction recovers with this time constant once peaks pass.
+const LIMITER_RELEASE_MS: f32 = 150.0;
+
+// The master bus stage applied to the mixed stream as it is pulled.
+// It fades in at startup, follows the live gain smoothly,
+// and fades out when the engine is stopping.
+// A limiter then keeps peaks under the ceiling.
+pub struct Master {
+    sample_rate: usize,
+    gain: f32,       // current linear gain, chasing the live setting
+    smoothing: f32,  // fraction of the way to the target per frame
+    level: f32,      // fade envelope from 0 to 1
+    level_step: f32, // per frame, negative while fading out
+    fading_out: bool,
+    limiter: Limiter,
+}
+
+impl Master {
+    pub fn new(sample_rate: usize, gain: f32, fade_in_ms: u32, ceiling_db: f32) -> Self {
+        let smoothing = 1.0 - (-1000.0 / (GAIN_SMOOTHING_MS * sample_rate as f32)).exp();
+        let mut master = Master {
+            sample_rate,
+            gain,
+            smoothing,
+            level: 1.0,
+            level_step: 0.0,
+            fading_out: false,
+            limiter: Limiter::new(sample_rate, ceiling_db),
+        };
+        if let Some(step) = master.step(fade_in_ms) {
+            master.level = 0.0;
+            master.level_step = step;
+        }
+        master
+    }
+
+    // Fade from the current level to silence.
+    pub fn fade_out(&mut self, fade_out_ms: u32) {
+        if self.fading_out {
+            return;
+        }
+        self.fading_out = true;
+        match self.step(fade_out_ms) {
+            Some(step) => self.level_step = -step,
+            None => self.level = 0.0,
+        }
+    }
+
+    pub fn faded_out(&self) -> bool {
+        self.fading_out && self.level <= 0.0
+    }
+
+    // Apply gain and fades to interleaved stereo in place.
+    // Returns how many samples were processed,
+    // which is less than buf.len() only once faded out.
+    pub fn process(&mut self, target_gain: f32, buf: &mut [f32]) -> usize {
+        for (i, frame) in buf.chunks_mut(2).enumerate() {
+            if self.faded_out() {
+                return i * 2;
+            }
+            self.gain += (target_gain - self.gain) * self.smoothing;
+            self.level = (self.level + self.level_step).clamp(0.0, 1.0);
+            for s in frame.iter_mut() {
+                *s *= self.gain * self.level;
+            }
+            self.limiter.process(frame);
+        }
+        buf.len()
+    }
+
+    // The per-frame level change for a fade, or None for no fade at all.
+    fn step(&self, ms: u32) -> Option<f32> {
+        let n_frames = ms as usize * self.sample_rate / 1000;
+        if n_frames == 0 {
+            None
+        } else {
+            Some(1.0 / n_frames as f32)
+        }
+    }
+}
+
+// A look-ahead peak limiter for interleaved stereo.
+// The output is delayed so gain reduction is in place before each peak,
+// with the target gain held over the look-ahead window and then box smoothed,
+// which keeps the gain at any peak no higher than that peak requires.
+struct Limiter {
+    ceiling: f32,
+    release: f32,               // fraction of the way back up per frame
+    delay: VecDeque<[f32; 2]>,  // frames waiting to be output
+    held: VecDeque<(u64, f32)>, // ascending minimum target gains with their frames
+    smoothing: VecDeque<f32>,   // recent released gains for the box filter
+    smoothing_sum: f64,
+    envelope: f32,
+    n_frames: u64,
+    window: usize,
+}
+
+impl Limiter {
+    fn new(sample_rate: usize, ceiling_db: f32) -> Self {
+        let window = std::cmp::max(1, LIMITER_LOOKAHEAD_MS * sample_rate / 1000);
+        let mut delay = VecDeque::with_capacity(window);
+        let mut smoothing = VecDeque::with_capacity(window);
+        for _ in 0..window {
+            smoothing.push_back(1.0);
+        }
+        // A peak is fully reduced once its whole window is in the box filter.
+        for _ in 1..window {
+            delay.push_back([0.0; 2]);
+        }
+        Limiter {
+            ceiling: 10.0_f32.powf(ceiling_db / 20.0),
+            release: 1.0 - (-1000.0 / (LIMITER_RELEASE_MS * sample_rate as f32)).exp(),
+            delay,
+            held: VecDeque::with_capacity(window + 1),
+            smoothing,
+            smoothing_sum: window as f64,
+            envelope: 1.0,
+            n_frames: 0,
+            window,
+        }
+    }
+
+    // Push one frame in and replace it with the delayed, limited frame.
+    fn process(&mut self, frame: &mut [f32]) {
+        let peak = frame.iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+        let target = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        // The lowest target over the look-ahead window.
+        while self.held.back().is_some_and(|(_, g)| *g >= target) {
+            self.held.pop_back();
+        }
+        self.held.push_back((self.n_frames, target));
+        while self
+            .held
+            .front()
+            .is_some_and(|(n, _)| *n + self.window as u64 <= self.n_frames)
+        {
+            self.held.pop_front();
+        }
+        self.n_frames += 1;
+        let held = self.held.front().map_or(1.0, |(_, g)| *g);
+
+        // Attack at once, release gradually.
+        if held < self.envelope {
+            self.envelope = held;
+        } else {
+            self.envelope += (held - self.envelope) * self.release;
+        }
+
+        self.smoothing_sum += self.envelope as f64 - self.smoothing.pop_front().unwrap() as f64;
+        self.smoothing.push_back(self.envelope);
+        let gain = (self.smoothing_sum / self.window as f64) as f32;
+
+        let mut input = [0.0; 2];
+        input.copy_from_slice(&frame[..2]);
+        self.delay.push_back(input);
+        let delayed = self.delay.pop_front().unwrap();
+        for (out, s) in frame.iter_mut().zip(delayed.iter()) {
+            // Rounding in the running sum must never let a peak through.
+            *out = (s * gain).clamp(-self.ceiling, self.ceiling);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // Run n_frames of a steady level through the limiter, returning the output.
+    fn run(limiter: &mut Limiter, level: f32, n_frames: usize) -> Vec<f32> {
+        let mut out = Vec::with_capacity(n_frames * 2);
+        for _ in 0..n_frames {
+            let mut frame = [level, -level];
+            limiter.process(&mut frame);
+            out.extend_from_slice(&frame);
+        }
+        out
+    }
+
+    #[test]
+    fn step_above_ceiling_is_held_under_it_and_released() {
+        let ceiling_db = -1.0;
+        let ceiling = 10.0_f32.powf(ceiling_db / 20.0);
+        let mut limiter = Limiter::new(SAMPLE_RATE, ceiling_db);
+        let quiet = 0.25;
+        let loud = 4.0; // 12 dB above full scale
+        let mut out = run(&mut limiter, quiet, SAMPLE_RATE / 2);
+        out.extend(run(&mut limiter, loud, SAMPLE_RATE / 2));
+        let loud_out = out.len() - SAMPLE_RATE / 4 * 2;
+        // ten release time constants
+        out.extend(run(&mut limiter, quiet, SAMPLE_RATE * 3 / 2));
+
+        let peak = out.iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+        assert!(peak <= ceiling, "peak {} over ceiling {}", peak, ceiling);
+        // The loud stretch is brought down to the ceiling, not far below it.
+        assert!((out[loud_out].abs() - ceiling).abs() < 0.01 * ceiling);
+        let last = out[out.len() - 1].abs();
+        assert!(
+            (last - quiet).abs() < 0.001,
+            "gain did not recover: {} for {}",
+            last,
+            quiet
+        );
+    }
+}
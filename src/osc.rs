@@ -0,0 +1,208 @@
+use rosc::{OscMessage, OscPacket, OscType};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::{io, thread};
+
+use crate::params::Params;
+
+// Listen for OSC messages that change live settings.
+//
+//     /acouwalk/grain_ms  int or float milliseconds
+//     /acouwalk/gain      float linear gain, as from a 0..1 fader
+//     /acouwalk/gain_db   float dB
+//     /acouwalk/n_grains  int or float count of grain streams mixed
+//     /acouwalk/density   int or float grains started per second, 0 for back to back
+//     /acouwalk/scene     string name or int position of a config file scene
+//
+// OSC has no replies, so rejected messages are only logged.
+pub fn serve(addr: SocketAddr, params: Arc<Params>) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    println!("OSC server listening on {}", addr);
+    thread::Builder::new()
+        .name("osc server".to_string())
+        .spawn(move || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            loop {
+                let (n, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("OSC server receiving: {}", e);
+                        continue;
+                    }
+                };
+                match rosc::decoder::decode_udp(&buf[..n]) {
+                    Ok((_, packet)) => handle_packet(packet, &params),
+                    Err(e) => eprintln!("OSC server decoding packet from {}: {:?}", from, e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn handle_packet(packet: OscPacket, params: &Params) {
+    match packet {
+        OscPacket::Message(msg) => {
+            println!("OSC message: {} {:?}", msg.addr, msg.args);
+            if let Err(e) = handle_message(&msg, params) {
+                eprintln!("OSC message {}: {}", msg.addr, e);
+            }
+        }
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                handle_packet(packet, params);
+            }
+        }
+    }
+}
+
+fn handle_message(msg: &OscMessage, params: &Params) -> Result<(), String> {
+    let arg = match msg.args.as_slice() {
+        [arg] => arg,
+        _ => return Err("expected exactly one argument".to_string()),
+    };
+    match msg.addr.as_str() {
+        "/acouwalk/grain_ms" => params.set_grain_ms(number(arg)?.round() as u32),
+        "/acouwalk/gain" => params.set_gain(number(arg)? as f32),
+        "/acouwalk/gain_db" => params.set_gain_db(number(arg)? as f32),
+        "/acouwalk/n_grains" => params.set_n_grains(number(arg)?.round() as u32),
+        "/acouwalk/density" => match number(arg)? {
+            0.0 => params.set_density(None),
+            n => params.set_density(Some(n as f32)),
+        },
+        "/acouwalk/scene" => match arg {
+            OscType::String(name) => params.recall_scene(name),
+            _ => match number(arg)? {
+                n if n >= 0.0 => params.recall_scene(&(n as usize).to_string()),
+                _ => Err("scene positions start at zero".to_string()),
+            },
+        },
+        _ => Err("unknown address".to_string()),
+    }
+}
+
+// Controllers differ in whether they send ints or floats.
+fn number(arg: &OscType) -> Result<f64, String> {
+    match arg {
+        OscType::Int(i) => Ok(*i as f64),
+        OscType::Long(i) => Ok(*i as f64),
+        OscType::Float(f) => Ok(*f as f64),
+        OscType::Double(f) => Ok(*f),
+        _ => Err(format!("expected a number, not {:?}", arg)),
+    }
+    .and_then(|n| {
+        if n.is_finite() {
+            Ok(n)
+        } else {
+            Err(format!("ill formed number {:?}", arg))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PlayConfig, Scene};
+
+    fn scene(name: &str, grain_ms: u32, n_grains: u32) -> Scene {
+        Scene {
+            name: name.to_string(),
+            grain_ms: Some(grain_ms),
+            n_grains: Some(n_grains),
+            gain_db: None,
+            len_cap_ms: None,
+            density: None,
+        }
+    }
+
+    fn params() -> Params {
+        let cfg = PlayConfig {
+            scenes: vec![scene("calm", 3000, 1), scene("busy", 200, 4)],
+            ..PlayConfig::default()
+        };
+        Params::new(&cfg)
+    }
+
+    fn send(params: &Params, addr: &str, arg: OscType) -> Result<(), String> {
+        let msg = OscMessage {
+            addr: addr.to_string(),
+            args: vec![arg],
+        };
+        handle_message(&msg, params)
+    }
+
+    #[test]
+    fn ints_and_floats_both_set_numbers() {
+        let params = params();
+        send(&params, "/acouwalk/grain_ms", OscType::Int(250)).unwrap();
+        assert_eq!(params.grain_ms(), Some(250));
+        send(&params, "/acouwalk/grain_ms", OscType::Float(300.4)).unwrap();
+        assert_eq!(params.grain_ms(), Some(300));
+        send(&params, "/acouwalk/n_grains", OscType::Float(2.0)).unwrap();
+        assert_eq!(params.n_grains(), 2);
+        send(&params, "/acouwalk/n_grains", OscType::Long(3)).unwrap();
+        assert_eq!(params.n_grains(), 3);
+        send(&params, "/acouwalk/gain_db", OscType::Int(-6)).unwrap();
+        assert!((params.gain_db() + 6.0).abs() < 1e-4);
+        send(&params, "/acouwalk/gain", OscType::Double(0.5)).unwrap();
+        assert!((params.gain() - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn zero_density_plays_back_to_back() {
+        let params = params();
+        send(&params, "/acouwalk/density", OscType::Int(40)).unwrap();
+        assert_eq!(params.density(), Some(40.0));
+        send(&params, "/acouwalk/density", OscType::Float(12.5)).unwrap();
+        assert_eq!(params.density(), Some(12.5));
+        send(&params, "/acouwalk/density", OscType::Float(0.0)).unwrap();
+        assert_eq!(params.density(), None);
+    }
+
+    #[test]
+    fn scenes_are_recalled_by_name_and_position() {
+        let params = params();
+        send(
+            &params,
+            "/acouwalk/scene",
+            OscType::String("busy".to_string()),
+        )
+        .unwrap();
+        assert_eq!((params.grain_ms(), params.n_grains()), (Some(200), 4));
+        send(&params, "/acouwalk/scene", OscType::Int(0)).unwrap();
+        assert_eq!((params.grain_ms(), params.n_grains()), (Some(3000), 1));
+        send(&params, "/acouwalk/scene", OscType::Float(1.0)).unwrap();
+        assert_eq!((params.grain_ms(), params.n_grains()), (Some(200), 4));
+        assert!(send(&params, "/acouwalk/scene", OscType::Int(2)).is_err());
+        assert!(send(&params, "/acouwalk/scene", OscType::Int(-1)).is_err());
+        assert!(send(
+            &params,
+            "/acouwalk/scene",
+            OscType::String("loud".to_string())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected_and_change_nothing() {
+        let params = params();
+        send(&params, "/acouwalk/grain_ms", OscType::Int(250)).unwrap();
+        for arg in [
+            OscType::String("long".to_string()),
+            OscType::Bool(true),
+            OscType::Float(f32::NAN),
+            OscType::Nil,
+            OscType::Int(0),
+            OscType::Int(100_000_000), // over an hour
+        ] {
+            assert!(send(&params, "/acouwalk/grain_ms", arg).is_err());
+        }
+        assert_eq!(params.grain_ms(), Some(250));
+        let two_args = OscMessage {
+            addr: "/acouwalk/grain_ms".to_string(),
+            args: vec![OscType::Int(100), OscType::Int(200)],
+        };
+        assert!(handle_message(&two_args, &params).is_err());
+        assert!(send(&params, "/acouwalk/grain", OscType::Int(100)).is_err());
+        assert_eq!(params.grain_ms(), Some(250));
+    }
+}
@@ -0,0 +1,133 @@
+use rtrb::{Consumer, RingBuffer};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::Engine;
+
+pub const DEFAULT_RING_MS: u32 = 500;
+const FEED_BLOCK_FRAMES: usize = 1024;
+const FEED_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+// The audio callback's end of a preallocated ring
+// that a feeder thread keeps topped up from an Engine.
+// Filling never blocks, allocates or prints,
+// so it is safe to call from JACK and cpal callbacks.
+pub struct RealtimeOutput {
+    ring: Consumer<f32>,
+    ended: Arc<AtomicBool>,
+    underruns: Arc<AtomicU64>,
+    played: Arc<AtomicU64>, // engine frames popped, for timing grain events
+    // Silence before the first grains arrive is not an underrun.
+    primed: bool,
+}
+
+impl RealtimeOutput {
+    pub fn new(mut engine: Engine, ring_ms: u32) -> Self {
+        let block_len = FEED_BLOCK_FRAMES * 2; // (2 for stereo)
+        let ring_len = std::cmp::max(
+            ring_ms as usize * engine.sample_rate() / 1000 * 2,
+            block_len * 2,
+        );
+        let played = engine.played_clock();
+        let (mut producer, consumer) = RingBuffer::new(ring_len);
+        let ended = Arc::new(AtomicBool::new(false));
+        let underruns = Arc::new(AtomicU64::new(0));
+        let feeder_ended = ended.clone();
+        let feeder_underruns = underruns.clone();
+        thread::Builder::new()
+            .name("ring feeder".to_string())
+            .spawn(move || {
+                let mut block: Vec<f32> = vec![0.0; block_len];
+                let mut n_underruns = 0;
+                loop {
+                    if producer.is_abandoned() {
+                        println!("ring feeder found the output gone");
+                        return;
+                    }
+                    let n = feeder_underruns.load(Ordering::Relaxed);
+                    if n != n_underruns {
+                        n_underruns = n;
+                        println!("output has had {} underruns", n_underruns);
+                    }
+                    if producer.slots() < block_len {
+                        thread::sleep(FEED_POLL_INTERVAL);
+                        continue;
+                    }
+                    let n_filled = engine.fill(&mut block);
+                    producer
+                        .push_entire_slice(&block[..n_filled])
+                        .expect("ring has room for a block");
+                    if n_filled < block_len {
+                        println!("ring feeder received EOF from engine");
+                        feeder_ended.store(true, Ordering::Release);
+                        return;
+                    }
+                }
+            })
+            .expect("spawning ring feeder");
+        RealtimeOutput {
+            ring: consumer,
+            ended,
+            underruns,
+            played,
+            primed: false,
+        }
+    }
+
+    // Fill buf with interleaved stereo, padding with silence on starvation.
+    // Returns false once the stream has ended and the ring is drained.
+    pub fn fill(&mut self, buf: &mut [f32]) -> bool {
+        let ended = self.ended.load(Ordering::Acquire);
+        let (popped, rest) = self.ring.pop_partial_slice(buf);
+        let n = popped.len();
+        for s in rest.iter_mut() {
+            *s = 0.0;
+        }
+        self.account(n, buf.len(), ended)
+    }
+
+    // Like fill, but for callbacks with a buffer per channel.
+    pub fn fill_split(&mut self, left: &mut [f32], right: &mut [f32]) -> bool {
+        let ended = self.ended.load(Ordering::Acquire);
+        let n_frames = std::cmp::min(left.len(), right.len());
+        let n = std::cmp::min(n_frames, self.ring.slots() / 2);
+        if let Ok(chunk) = self.ring.read_chunk(n * 2) {
+            let (a, b) = chunk.as_slices();
+            for (i, s) in a.iter().chain(b.iter()).enumerate() {
+                if i % 2 == 0 {
+                    left[i / 2] = *s;
+                } else {
+                    right[i / 2] = *s;
+                }
+            }
+            chunk.commit_all();
+        }
+        for i in n..n_frames {
+            left[i] = 0.0;
+            right[i] = 0.0;
+        }
+        self.account(n * 2, n_frames * 2, ended)
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    fn account(&mut self, n: usize, wanted: usize, ended: bool) -> bool {
+        if n > 0 {
+            self.primed = true;
+            self.played.fetch_add((n / 2) as u64, Ordering::Relaxed);
+        }
+        if n < wanted {
+            if ended {
+                return false;
+            }
+            if self.primed {
+                self.underruns.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,153 @@
+use rand::Rng;
+use rand_distr::{Distribution, Uniform};
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::fmt::{self, Display};
+
+// A per-grain setting, either fixed, drawn at random from a range,
+// or sweeping slowly back and forth between two ends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Motion {
+    Fixed(f32),
+    Uniform(f32, f32),    // low, high
+    Sweep(f32, f32, f32), // one end, the other, seconds per round trip
+}
+
+impl Motion {
+    // The value for a grain starting secs into the stream.
+    // Streams sweep with their own phase, from 0 to 1,
+    // so they do not all move together.
+    pub fn value<R: Rng>(&self, secs: f64, phase: f32, rng: &mut R) -> f32 {
+        match *self {
+            Motion::Fixed(v) => v,
+            Motion::Uniform(low, high) if low < high => Uniform::from(low..high).sample(rng),
+            Motion::Uniform(low, _) => low,
+            Motion::Sweep(a, b, period) => {
+                let cycle = (secs / period as f64) as f32 + phase;
+                let x = 0.5 - 0.5 * (2.0 * PI * cycle).cos();
+                a + (b - a) * x
+            }
+        }
+    }
+
+    // Motions are written as a value, uniform:LOW,HIGH or sweep:FROM,TO,SECS,
+    // with every value from min to max.
+    pub fn parse(s: &str, name: &str, min: f32, max: f32) -> Result<Self, String> {
+        let values: Vec<f32> = match s.split_once(':') {
+            Some((_, args)) => args.split(',').map(|a| a.trim().parse::<f32>()).collect(),
+            None => s.trim().parse::<f32>().map(|v| vec![v]),
+        }
+        .map_err(|_| format!("ill formed {} {:?}", name, s))?;
+        let motion = match (s.split_once(':').map(|(kind, _)| kind), values.as_slice()) {
+            (None, [v]) => Motion::Fixed(*v),
+            (Some("uniform"), [low, high]) => Motion::Uniform(*low, *high),
+            (Some("sweep"), [a, b, period]) => Motion::Sweep(*a, *b, *period),
+            _ => {
+                return Err(format!(
+                    "{} {:?} is not a value, uniform:LOW,HIGH or sweep:FROM,TO,SECS",
+                    name, s
+                ))
+            }
+        };
+        let in_range = |v: &f32| (min..=max).contains(v);
+        let ok = match motion {
+            Motion::Fixed(v) => in_range(&v),
+            Motion::Uniform(low, high) => in_range(&low) && in_range(&high) && low <= high,
+            Motion::Sweep(a, b, period) => {
+                in_range(&a) && in_range(&b) && period > 0.0 && period.is_finite()
+            }
+        };
+        if ok {
+            Ok(motion)
+        } else {
+            Err(format!(
+                "{} {:?} must stay from {} to {}, with ranges from low to high and positive periods",
+                name, s, min, max
+            ))
+        }
+    }
+}
+
+impl Display for Motion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Motion::Fixed(v) => write!(f, "{}", v),
+            Motion::Uniform(low, high) => write!(f, "uniform:{},{}", low, high),
+            Motion::Sweep(a, b, period) => write!(f, "sweep:{},{},{}", a, b, period),
+        }
+    }
+}
+
+// Place interleaved stereo at pan, from -1 left to 1 right,
+// narrowed by width, from 0 for mono to 1 for the recorded image.
+// What the channels share is panned with the equal-power law,
+// so a mono source keeps its power wherever it goes.
+// Their difference spreads it around that place,
+// less toward the edges so the image stays within the stereo field.
+pub fn pan_frames(stereo: &mut [f32], pan: f32, width: f32) {
+    if pan == 0.0 && width == 1.0 {
+        return; // as recorded
+    }
+    let (mid_l, mid_r) = equal_power(pan);
+    let spread = width * (1.0 - pan.abs());
+    for frame in stereo.chunks_exact_mut(2) {
+        let (l, r) = (frame[0], frame[1]);
+        let mid = (l + r) * FRAC_1_SQRT_2;
+        let side = (l - r) * 0.5 * spread;
+        frame[0] = mid * mid_l + side;
+        frame[1] = mid * mid_r - side;
+    }
+}
+
+// Left and right gains for a source at pos, from -1 to 1.
+fn equal_power(pos: f32) -> (f32, f32) {
+    let theta = (pos.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+    (theta.cos(), theta.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PANS: [f32; 7] = [-1.0, -0.6, -0.2, 0.0, 0.3, 0.7, 1.0];
+
+    fn power(frame: &[f32]) -> f32 {
+        frame[0] * frame[0] + frame[1] * frame[1]
+    }
+
+    #[test]
+    fn mono_keeps_its_power_across_pans() {
+        let s = 0.5;
+        let recorded = power(&[s, s]);
+        for pan in PANS {
+            for width in [0.0, 0.5, 1.0] {
+                let mut frame = [s, s];
+                pan_frames(&mut frame, pan, width);
+                assert!(
+                    (power(&frame) - recorded).abs() < 1e-6,
+                    "power {} at pan {} width {}, recorded {}",
+                    power(&frame),
+                    pan,
+                    width,
+                    recorded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn zero_width_collapses_to_the_pan_position() {
+        for pan in PANS {
+            let (gain_l, gain_r) = equal_power(pan);
+            let mut frames = [0.8, 0.0, 0.0, 0.8, 0.3, -0.1]; // left, right, mixed
+            pan_frames(&mut frames, pan, 0.0);
+            for (frame, (l, r)) in frames
+                .chunks_exact(2)
+                .zip([(0.8, 0.0), (0.0, 0.8), (0.3, -0.1)])
+            {
+                let mid = (l + r) * FRAC_1_SQRT_2;
+                assert!((frame[0] - mid * gain_l).abs() < 1e-6);
+                assert!((frame[1] - mid * gain_r).abs() < 1e-6);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::config::{PlayConfig, Scene};
+use crate::grain::MAX_GRAIN_MS;
+use crate::schedule::MAX_DENSITY;
+
+const MAX_GAIN_DB: f32 = 24.0;
+
+// Settings that may change while the engine runs.
+// Grain makers, the mixer and the wav picker read these as they go.
+pub struct Params {
+    grain_ms: AtomicU32, // zero for the default grain length
+    n_grains: AtomicU32,
+    max_n_grains: u32,
+    gain: AtomicU32,    // bits of the f32 linear gain
+    cap_ms: AtomicU32,  // zero for no cap
+    density: AtomicU32, // bits of the f32 grains per second, zero for back to back
+    excluded: Mutex<HashSet<path::PathBuf>>,
+    // bumped when the pool of wavs to pick from must be rebuilt
+    pool_generation: AtomicU32,
+    scenes: Vec<Scene>,
+    fade_out_ms: u32,
+    stopping: AtomicBool,
+}
+
+impl Params {
+    pub fn new(cfg: &PlayConfig) -> Self {
+        Params {
+            grain_ms: AtomicU32::new(cfg.grain_ms.unwrap_or(0)),
+            n_grains: AtomicU32::new(cfg.grain.n_grains),
+            max_n_grains: cfg.grain.n_grains,
+            gain: AtomicU32::new(10.0_f32.powf(cfg.gain_db / 20.0).to_bits()),
+            cap_ms: AtomicU32::new(cfg.cap_ms.unwrap_or(0)),
+            density: AtomicU32::new(cfg.density.unwrap_or(0.0).to_bits()),
+            excluded: Mutex::new(cfg.excluded_wavs.clone()),
+            pool_generation: AtomicU32::new(0),
+            scenes: cfg.scenes.clone(),
+            fade_out_ms: cfg.fade_out_ms,
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    pub fn grain_ms(&self) -> Option<u32> {
+        match self.grain_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    pub fn set_grain_ms(&self, grain_ms: u32) -> Result<(), String> {
+        if grain_ms == 0 || grain_ms > MAX_GRAIN_MS {
+            return Err(format!(
+                "grain milliseconds must be from 1 to {}",
+                MAX_GRAIN_MS
+            ));
+        }
+        self.grain_ms.store(grain_ms, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // How many of the grain streams are mixed.
+    pub fn n_grains(&self) -> u32 {
+        self.n_grains.load(Ordering::Relaxed)
+    }
+
+    pub fn max_n_grains(&self) -> u32 {
+        self.max_n_grains
+    }
+
+    pub fn set_n_grains(&self, n_grains: u32) -> Result<(), String> {
+        if n_grains == 0 || n_grains > self.max_n_grains {
+            return Err(format!(
+                "the grain count must be from 1 to {}",
+                self.max_n_grains
+            ));
+        }
+        self.n_grains.store(n_grains, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        20.0 * self.gain().log10()
+    }
+
+    pub fn set_gain_db(&self, db: f32) -> Result<(), String> {
+        if !db.is_finite() || db > MAX_GAIN_DB {
+            return Err(format!(
+                "the gain must be a finite number of dB up to {}",
+                MAX_GAIN_DB
+            ));
+        }
+        self.set_gain(10.0_f32.powf(db / 20.0))
+    }
+
+    // Linear gain, as from a fader running from zero to one.
+    pub fn set_gain(&self, gain: f32) -> Result<(), String> {
+        let max_gain = 10.0_f32.powf(MAX_GAIN_DB / 20.0);
+        if !(0.0..=max_gain).contains(&gain) {
+            return Err(format!("the linear gain must be from 0 to {:.1}", max_gain));
+        }
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn cap_ms(&self) -> Option<u32> {
+        match self.cap_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    pub fn set_cap_ms(&self, cap_ms: Option<u32>) -> Result<(), String> {
+        if cap_ms == Some(0) {
+            return Err("the length cap must be positive".to_string());
+        }
+        self.cap_ms.store(cap_ms.unwrap_or(0), Ordering::Relaxed);
+        self.pool_generation.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Grains started per second across all mixed streams,
+    // or None for each stream playing its grains back to back.
+    pub fn density(&self) -> Option<f32> {
+        match f32::from_bits(self.density.load(Ordering::Relaxed)) {
+            d if d > 0.0 => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn set_density(&self, density: Option<f32>) -> Result<(), String> {
+        if density.is_some_and(|d| !(d > 0.0 && d <= MAX_DENSITY)) {
+            return Err(format!(
+                "the density must be above 0 and at most {} grains per second",
+                MAX_DENSITY
+            ));
+        }
+        self.density
+            .store(density.unwrap_or(0.0).to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn is_excluded(&self, wav: &path::Path) -> bool {
+        self.excluded.lock().unwrap().contains(wav)
+    }
+
+    pub fn n_excluded(&self) -> usize {
+        self.excluded.lock().unwrap().len()
+    }
+
+    pub fn exclude(&self, wav: path::PathBuf) {
+        if self.excluded.lock().unwrap().insert(wav) {
+            self.pool_generation.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Only files excluded at runtime come back,
+    // since files excluded at startup were never surveyed.
+    pub fn include(&self, wav: &path::Path) {
+        if self.excluded.lock().unwrap().remove(wav) {
+            self.pool_generation.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn pool_generation(&self) -> u32 {
+        self.pool_generation.load(Ordering::Relaxed)
+    }
+
+    pub fn fade_out_ms(&self) -> u32 {
+        self.fade_out_ms
+    }
+
+    // Ask the engine to fade out and end the stream.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    pub fn scene_names(&self) -> Vec<&str> {
+        self.scenes.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    // Recall a scene from the configuration file by name or by position.
+    pub fn recall_scene(&self, which: &str) -> Result<(), String> {
+        let scene = match which.parse::<usize>() {
+            Ok(i) => self.scenes.get(i),
+            Err(_) => self.scenes.iter().find(|s| s.name == which),
+        };
+        match scene {
+            Some(scene) => self.apply_scene(scene),
+            None => Err(format!("no scene {:?}", which)),
+        }
+    }
+
+    // Settings a scene leaves out keep their current values.
+    pub fn apply_scene(&self, scene: &Scene) -> Result<(), String> {
+        if let Some(grain_ms) = scene.grain_ms {
+            self.set_grain_ms(grain_ms)?;
+        }
+        if let Some(n_grains) = scene.n_grains {
+            self.set_n_grains(n_grains)?;
+        }
+        if let Some(db) = scene.gain_db {
+            self.set_gain_db(db)?;
+        }
+        if let Some(cap_ms) = scene.len_cap_ms {
+            self.set_cap_ms(Some(cap_ms))?;
+        }
+        if let Some(density) = scene.density {
+            self.set_density(Some(density))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use rand::Rng;
+use rand_distr::{Distribution, Normal, Uniform};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+// Grains shift at most this many semitones either way.
+pub const MAX_SEMITONES: f32 = 36.0;
+
+// How far each grain is shifted in pitch, in semitones,
+// by playing it faster or slower.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pitch {
+    Fixed(f32),
+    Uniform(f32, f32), // low, high
+    Normal(f32, f32),  // mean, standard deviation
+}
+
+impl Pitch {
+    // The playback rate for the next grain, where 2 is an octave up.
+    pub fn rate<R: Rng>(&self, rng: &mut R) -> f32 {
+        let semitones = match *self {
+            Pitch::Fixed(st) => st,
+            Pitch::Uniform(low, high) if low < high => Uniform::from(low..high).sample(rng),
+            Pitch::Uniform(low, _) => low,
+            Pitch::Normal(mean, sd) => Normal::new(mean, sd)
+                .expect("finite pitch deviation")
+                .sample(rng)
+                .clamp(-MAX_SEMITONES, MAX_SEMITONES),
+        };
+        2.0_f32.powf(semitones / 12.0)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let in_range = |st: f32| st.is_finite() && st.abs() <= MAX_SEMITONES;
+        let ok = match *self {
+            Pitch::Fixed(st) => in_range(st),
+            Pitch::Uniform(low, high) => in_range(low) && in_range(high) && low <= high,
+            Pitch::Normal(mean, sd) => in_range(mean) && in_range(sd) && sd >= 0.0,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!(
+                "pitch {} must stay within {} semitones, with ranges from low to high",
+                self, MAX_SEMITONES
+            ))
+        }
+    }
+}
+
+// Amounts are semitones, or cents with a "c" suffix,
+// as in -12, 700c, uniform:-2,2 or normal:0,30c.
+impl FromStr for Pitch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let amount = |a: &str| {
+            let a = a.trim();
+            match a.strip_suffix('c') {
+                Some(cents) => cents.parse::<f32>().map(|c| c / 100.0),
+                None => a.parse::<f32>(),
+            }
+            .map_err(|_| format!("ill formed pitch amount {:?} in {:?}", a, s))
+        };
+        let pair = |args: &str| match args.split_once(',') {
+            Some((a, b)) => Ok((amount(a)?, amount(b)?)),
+            None => Err(format!("pitch {:?} needs two amounts", s)),
+        };
+        let pitch = match s.split_once(':') {
+            Some(("uniform", args)) => {
+                let (low, high) = pair(args)?;
+                Pitch::Uniform(low, high)
+            }
+            Some(("normal", args)) => {
+                let (mean, sd) = pair(args)?;
+                Pitch::Normal(mean, sd)
+            }
+            Some(_) => {
+                return Err(format!(
+                    "pitch {:?} is not an amount, uniform:LOW,HIGH or normal:MEAN,SD",
+                    s
+                ))
+            }
+            None => Pitch::Fixed(amount(s)?),
+        };
+        pitch.validate()?;
+        Ok(pitch)
+    }
+}
+
+impl Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pitch::Fixed(st) => write!(f, "{}", st),
+            Pitch::Uniform(low, high) => write!(f, "uniform:{},{}", low, high),
+            Pitch::Normal(mean, sd) => write!(f, "normal:{},{}", mean, sd),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use acoustic_walk::config::RenderConfig;
+use acoustic_walk::Engine;
+use crossbeam_channel::Sender;
+
+const RENDER_BUF_N_SAMPLES: usize = 64 * 1024;
+
+// Write the mixed stream to a WAV file as fast as the samples arrive.
+pub fn render_to_wav(cfg: RenderConfig, done_tx: Sender<()>, mut engine: Engine) {
+    println!("render_to_wav starting for {:?}", cfg.outfile);
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: cfg.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(&cfg.outfile, spec).expect("creating output WAV");
+    let n_frames = cfg.duration_ms as usize * cfg.sample_rate / 1000;
+    let mut n_written = 0;
+    let mut samples: Vec<f32> = vec![0.0; RENDER_BUF_N_SAMPLES];
+    while n_written < n_frames {
+        let n = std::cmp::min(n_frames - n_written, RENDER_BUF_N_SAMPLES / 2);
+        let n_filled = engine.fill(&mut samples[..(n * 2)]);
+        for s in samples.iter().take(n_filled) {
+            writer.write_sample(*s).expect("writing output WAV");
+        }
+        n_written += n_filled / 2;
+        println!("render wrote {} of {} stereo samples", n_written, n_frames);
+        if n_filled < n * 2 {
+            println!("render received EOF from engine");
+            break;
+        }
+    }
+    writer.finalize().expect("finalizing output WAV");
+    println!("render_to_wav is done");
+    done_tx.send(()).unwrap();
+}
@@ -4,8 +4,6 @@ use std::fmt::{self, Display};
 use std::time::{Duration, SystemTime};
 use std::{io, path, thread};
 
-pub const DEFAULT_N_ENTRIES: usize = 1024;
-
 #[derive(Debug)]
 struct Entry {
     buf: String,
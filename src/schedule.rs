@@ -0,0 +1,48 @@
+use rand::Rng;
+use rand_distr::{Distribution, Exp};
+use std::str::FromStr;
+
+// The most grains per second the whole engine may be asked for.
+pub const MAX_DENSITY: f32 = 1000.0;
+
+// How grain onsets are spaced at a given density.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Spacing {
+    Poisson,  // random, like rain
+    Periodic, // evenly, like a clock
+}
+
+impl FromStr for Spacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "poisson" => Ok(Spacing::Poisson),
+            "periodic" => Ok(Spacing::Periodic),
+            _ => Err(format!("spacing {:?} is neither poisson nor periodic", s)),
+        }
+    }
+}
+
+impl Spacing {
+    // Frames from one onset to the next in a stream of rate grains per second.
+    pub fn interval<R: Rng>(&self, rate: f32, sample_rate: usize, rng: &mut R) -> usize {
+        let mean = sample_rate as f32 / rate;
+        match self {
+            Spacing::Periodic => mean.round() as usize,
+            Spacing::Poisson => Exp::new(1.0 / mean)
+                .expect("positive grain rate")
+                .sample(rng)
+                .round() as usize,
+        }
+    }
+
+    // Where a stream's first onset falls, so streams do not start in lockstep.
+    pub fn first_onset<R: Rng>(&self, rate: f32, sample_rate: usize, rng: &mut R) -> usize {
+        let mean = (sample_rate as f32 / rate).round() as usize;
+        match self {
+            Spacing::Periodic => rng.gen_range(0..=mean),
+            Spacing::Poisson => self.interval(rate, sample_rate, rng),
+        }
+    }
+}
@@ -1,22 +1,185 @@
+use crate::catalog::{self, Catalog};
 use crate::config::PlayConfig;
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crate::decode::{Format, Probe};
+use crate::energy::EnergyMap;
+use crate::loudness::Loudness;
+use crate::params::Params;
+use crossbeam_channel::{bounded, Receiver, Select, Sender};
 use probability::prelude::*;
+use rand::rngs::StdRng;
+use rand::Rng;
 use rand_distr::Dirichlet;
 use rand_distr::Distribution;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{fs, io, path, thread};
 
+const POOL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct WavDesc {
     pub path: path::PathBuf,
     pub n_samples: u32,
     pub spec: hound::WavSpec,
+    pub format: Format,
+    pub kind: SampleKind,
+    pub channel_map: ChannelMap,
     pub ms_for_choice: f32,
+    pub loudness: Option<Loudness>, // None unless measured
+    pub energy: Arc<EnergyMap>,     // empty unless measured
+}
+
+// How a file's channels are reduced to the stereo stream.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChannelMap {
+    Mono,
+    Pair(u16, u16),
+    Downmix,
+}
+
+// Configured mapping for files with more than one channel.
+#[derive(Clone, Copy, Debug)]
+pub enum ChannelPolicy {
+    Downmix,
+    Pair(u16, u16),
+}
+
+impl FromStr for ChannelPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("downmix") {
+            return Ok(ChannelPolicy::Downmix);
+        }
+        let pair: Vec<&str> = s.split(',').collect();
+        if pair.len() == 2 {
+            if let (Ok(l), Ok(r)) = (pair[0].trim().parse(), pair[1].trim().parse()) {
+                return Ok(ChannelPolicy::Pair(l, r));
+            }
+        }
+        Err(format!(
+            "channel policy {:?} is neither \"downmix\" nor a pair like \"0,1\"",
+            s
+        ))
+    }
+}
+
+impl ChannelMap {
+    // Policies only choose how multichannel files are reduced,
+    // so mono and stereo files always play as they are.
+    pub fn resolve(n_channels: u16, policy: ChannelPolicy) -> Option<Self> {
+        match (n_channels, policy) {
+            (0, _) => None,
+            (1, _) => Some(ChannelMap::Mono),
+            (2, _) => Some(ChannelMap::Pair(0, 1)),
+            (n, ChannelPolicy::Pair(l, r)) if l < n && r < n => Some(ChannelMap::Pair(l, r)),
+            (_, ChannelPolicy::Pair(_, _)) => None,
+            (_, ChannelPolicy::Downmix) => Some(ChannelMap::Downmix),
+        }
+    }
+
+    // Turn interleaved source frames into interleaved stereo frames.
+    // Mono sits in the center at equal power,
+    // and a downmix averages even channels to the left, odd to the right.
+    pub fn to_stereo(self, n_channels: u16, samples: &[f32]) -> Vec<f32> {
+        let n_channels = n_channels as usize;
+        let mut stereo: Vec<f32> = Vec::with_capacity(samples.len() / n_channels * 2);
+        for frame in samples.chunks_exact(n_channels) {
+            match self {
+                ChannelMap::Mono => {
+                    let s = frame[0] * std::f32::consts::FRAC_1_SQRT_2;
+                    stereo.push(s);
+                    stereo.push(s);
+                }
+                ChannelMap::Pair(l, r) => {
+                    stereo.push(frame[l as usize]);
+                    stereo.push(frame[r as usize]);
+                }
+                ChannelMap::Downmix => {
+                    let n_right = n_channels / 2;
+                    let n_left = n_channels - n_right;
+                    let left: f32 = frame.iter().step_by(2).sum();
+                    let right: f32 = frame.iter().skip(1).step_by(2).sum();
+                    stereo.push(left / n_left as f32);
+                    stereo.push(right / n_right as f32);
+                }
+            }
+        }
+        stereo
+    }
 }
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum SampleKind {
+    Int(u16),
+    Float,
+}
+
+impl SampleKind {
+    pub fn from_spec(spec: &hound::WavSpec) -> Option<Self> {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Int, bits) if (8..=32).contains(&bits) => {
+                Some(SampleKind::Int(bits))
+            }
+            (hound::SampleFormat::Float, 32) => Some(SampleKind::Float),
+            _ => None,
+        }
+    }
+}
+
+// Read up to n interleaved samples normalized to [-1.0, 1.0],
+// noting whether any sample sits at full scale.
+// A file cut short of its header's length, as while it is still being copied,
+// ends the read early.
+pub fn read_samples(
+    reader: &mut hound::WavReader<io::BufReader<fs::File>>,
+    kind: SampleKind,
+    n: usize,
+    too_loud: &mut bool,
+) -> Vec<f32> {
+    match kind {
+        SampleKind::Int(bits) => {
+            let full_scale = (1_i64 << (bits - 1)) as f32;
+            let max = (1_i64 << (bits - 1)) - 1;
+            let min = -(1_i64 << (bits - 1));
+            reader
+                .samples::<i32>()
+                .take(n)
+                .map_while(Result::ok)
+                .map(|s| {
+                    let s = s as i64;
+                    if s == max || s == min {
+                        *too_loud = true;
+                    }
+                    s as f32 / full_scale
+                })
+                .collect()
+        }
+        SampleKind::Float => reader
+            .samples::<f32>()
+            .take(n)
+            .map_while(Result::ok)
+            .inspect(|s| {
+                if s.abs() >= 1.0 {
+                    *too_loud = true;
+                }
+            })
+            .collect(),
+    }
+}
+
+// Each grain maker gets its own pick channel and RNG,
+// so picks stay reproducible no matter which maker asks first.
 pub fn start_wav_picker(
     n_producers: u32,
+    mut rngs: Vec<StdRng>,
     wdescs_rx: Receiver<Option<WavDesc>>,
-) -> Receiver<WavDesc> {
+    rescans_rx: Receiver<Vec<WavDesc>>,
+    params: Arc<Params>,
+) -> Vec<Receiver<WavDesc>> {
     let mut n = n_producers;
     let mut wavs: Vec<WavDesc> = Vec::new();
     while n > 0 {
@@ -32,52 +195,194 @@ pub fn start_wav_picker(
             n -= 1;
         }
     }
+    // Surveyors finish in arbitrary order.
+    wavs.sort_by(|a, b| a.path.cmp(&b.path));
     println!(
         "WAV picker collected {} wav descriptions - spawning thread",
         wavs.len()
     );
-    let (wavpick_tx, wavpick_rx) = bounded(0);
+    let mut wavpick_txs: Vec<Sender<WavDesc>> = Vec::new();
+    let mut wavpick_rxs: Vec<Receiver<WavDesc>> = Vec::new();
+    for _ in 0..rngs.len() {
+        let (wavpick_tx, wavpick_rx) = bounded(0);
+        wavpick_txs.push(wavpick_tx);
+        wavpick_rxs.push(wavpick_rx);
+    }
+    let mut surveyed = wavs;
     thread::Builder::new()
         .name("wav selector".to_string())
         .spawn(move || {
-            while let Some(which) = crate::wav::select_wavs(&wavs, 1) {
-                let which = which.iter().take(1).copied().last().unwrap();
-                let wav = &wavs[which];
+            let mut pool_generation = params.pool_generation();
+            let mut wavs = pick_pool(&surveyed, &params);
+            let mut picks: Vec<usize> = Vec::new();
+            if !wavs.is_empty() {
+                for rng in rngs.iter_mut() {
+                    picks.push(pick_wav(&wavs, rng));
+                }
+            }
+            let mut live = vec![true; wavpick_txs.len()];
+            let mut rescans_open = true;
+            loop {
+                if surveyed.is_empty() && !rescans_open {
+                    return;
+                }
+                if params.pool_generation() != pool_generation {
+                    pool_generation = params.pool_generation();
+                    wavs = pick_pool(&surveyed, &params);
+                    println!("WAV picker pool now has {} wav descriptions", wavs.len());
+                    if !wavs.is_empty() {
+                        picks = rngs.iter_mut().map(|rng| pick_wav(&wavs, rng)).collect();
+                    }
+                }
+                // The pool can change between picks, so the select is rebuilt each time.
+                let mut sel = Select::new();
+                let mut senders: Vec<(usize, usize)> = Vec::new(); // (oper, maker)
+                if !wavs.is_empty() {
+                    for (i, wavpick_tx) in wavpick_txs.iter().enumerate() {
+                        if live[i] {
+                            senders.push((sel.send(wavpick_tx), i));
+                        }
+                    }
+                    if senders.is_empty() {
+                        return;
+                    }
+                }
+                let rescan_oper = if rescans_open {
+                    Some(sel.recv(&rescans_rx))
+                } else {
+                    None
+                };
+                let oper = if wavs.is_empty() {
+                    // Nothing to send, but exclusions may be lifted at any time.
+                    sel.select_timeout(POOL_POLL_INTERVAL).ok()
+                } else {
+                    Some(sel.select())
+                };
+                let oper = match oper {
+                    Some(oper) => oper,
+                    None => continue,
+                };
+                if Some(oper.index()) == rescan_oper {
+                    match oper.recv(&rescans_rx) {
+                        Ok(fresh) => {
+                            if replace_wavs(&mut surveyed, fresh) {
+                                wavs = pick_pool(&surveyed, &params);
+                                if !wavs.is_empty() {
+                                    picks =
+                                        rngs.iter_mut().map(|rng| pick_wav(&wavs, rng)).collect();
+                                }
+                            }
+                        }
+                        Err(_) => rescans_open = false,
+                    }
+                    continue;
+                }
+                let (_, i) = *senders
+                    .iter()
+                    .find(|(o, _)| *o == oper.index())
+                    .expect("selected a known operation");
+                let wav = &wavs[picks[i]];
+                if oper.send(&wavpick_txs[i], wav.clone()).is_err() {
+                    live[i] = false;
+                    continue;
+                }
                 println!("wav picker: {:?}", wav.path);
-                wavpick_tx.send(wav.clone()).unwrap();
+                picks[i] = pick_wav(&wavs, &mut rngs[i]);
             }
         })
         .expect("spawning wav selector");
 
-    wavpick_rx
+    wavpick_rxs
 }
 
-pub fn describe_wav(path: path::PathBuf, cap_ms: Option<u32>) -> Option<WavDesc> {
-    if let Ok(reader) = hound::WavReader::open(&path) {
-        if reader.spec().channels != 2 {
-            None
-        } else {
+// The surveyed wavs that may be picked under the current cap and exclusions.
+fn pick_pool(surveyed: &[WavDesc], params: &Params) -> Vec<WavDesc> {
+    let cap_ms = params.cap_ms();
+    surveyed
+        .iter()
+        .filter(|w| !params.is_excluded(&w.path))
+        .map(|w| {
+            let mut w = w.clone();
+            let ms = wav_ms(&w.spec, w.n_samples);
+            w.ms_for_choice = match cap_ms {
+                Some(cap_ms) => ms.min(cap_ms as f32),
+                None => ms,
+            };
+            w
+        })
+        .collect()
+}
+
+fn pick_wav<R: Rng>(wavs: &[WavDesc], rng: &mut R) -> usize {
+    select_wavs(wavs, 1, rng).expect("picking from a non-empty pool")[0]
+}
+
+// Swap in the result of a rescan, reporting whether the pool changed.
+fn replace_wavs(wavs: &mut Vec<WavDesc>, mut fresh: Vec<WavDesc>) -> bool {
+    fresh.sort_by(|a, b| a.path.cmp(&b.path));
+    let same = |a: &WavDesc, b: &WavDesc| {
+        a.path == b.path && a.n_samples == b.n_samples && a.ms_for_choice == b.ms_for_choice
+    };
+    if wavs.len() == fresh.len() && wavs.iter().zip(fresh.iter()).all(|(a, b)| same(a, b)) {
+        return false;
+    }
+    let old: HashSet<&path::Path> = wavs.iter().map(|w| w.path.as_path()).collect();
+    let new: HashSet<&path::Path> = fresh.iter().map(|w| w.path.as_path()).collect();
+    for p in new.difference(&old) {
+        println!("rescan added {:?}", p);
+    }
+    for p in old.difference(&new) {
+        println!("rescan removed {:?}", p);
+    }
+    println!("WAV picker now has {} wav descriptions", fresh.len());
+    *wavs = fresh;
+    true
+}
+
+pub fn describe_wav(
+    path: path::PathBuf,
+    format: Format,
+    probe: Probe,
+    cap_ms: Option<u32>,
+    policy: ChannelPolicy,
+) -> Option<WavDesc> {
+    if probe.n_samples < 2 {
+        println!("skipping {:?} with {} frames", path, probe.n_samples);
+        return None;
+    }
+    let channel_map = ChannelMap::resolve(probe.spec.channels, policy);
+    match channel_map {
+        Some(channel_map) => {
             let path_str = format!("{:?}", path);
             Some(WavDesc {
                 path,
-                n_samples: reader.duration(),
-                spec: reader.spec(),
-                ms_for_choice: capped_ms(&path_str, reader, cap_ms),
+                n_samples: probe.n_samples,
+                spec: probe.spec,
+                format,
+                kind: probe.kind,
+                channel_map,
+                ms_for_choice: capped_ms(&path_str, &probe.spec, probe.n_samples, cap_ms),
+                loudness: probe.loudness,
+                energy: Arc::new(probe.energy.unwrap_or_default()),
             })
         }
-    } else {
-        None
+        None => {
+            println!(
+                "skipping {:?} with {} channels for channel policy {:?}",
+                path, probe.spec.channels, policy
+            );
+            None
+        }
     }
 }
 
-fn capped_ms(
-    path: &str,
-    reader: hound::WavReader<io::BufReader<fs::File>>,
-    cap_ms: Option<u32>,
-) -> f32 {
-    let sr_ms = (reader.spec().sample_rate as f32) / 1000.0;
-    let n_samples = reader.duration() as f32;
-    let wav_ms = n_samples / sr_ms;
+fn wav_ms(spec: &hound::WavSpec, n_samples: u32) -> f32 {
+    let sr_ms = (spec.sample_rate as f32) / 1000.0;
+    n_samples as f32 / sr_ms
+}
+
+fn capped_ms(path: &str, spec: &hound::WavSpec, n_samples: u32, cap_ms: Option<u32>) -> f32 {
+    let wav_ms = wav_ms(spec, n_samples);
     match cap_ms {
         None => wav_ms,
         Some(cap_ms) => {
@@ -92,14 +397,28 @@ fn capped_ms(
     }
 }
 
-pub fn select_wavs(wavs: &[WavDesc], n: usize) -> Option<Vec<usize>> {
+pub fn select_wavs<R: Rng>(wavs: &[WavDesc], n: usize, rng: &mut R) -> Option<Vec<usize>> {
     if wavs.is_empty() {
         return None;
     }
+    if wavs.len() == 1 {
+        return Some(vec![0; n]);
+    }
     let lens: Vec<f64> = wavs.iter().map(|e| e.ms_for_choice as f64).collect();
-    let dirichlet = Dirichlet::new(&lens).unwrap();
-    let mut source = source::default();
-    let probs = dirichlet.sample(&mut rand::thread_rng());
+    // The Dirichlet needs every length positive,
+    // so otherwise files are picked in proportion to length,
+    // or evenly when none has any.
+    let probs = if lens.iter().all(|l| *l > 0.0) {
+        Dirichlet::new(&lens).unwrap().sample(rng)
+    } else {
+        let total: f64 = lens.iter().sum();
+        if total > 0.0 {
+            lens.iter().map(|l| l / total).collect()
+        } else {
+            vec![1.0 / lens.len() as f64; lens.len()]
+        }
+    };
+    let mut source = source::default().seed([rng.gen(), rng.gen()]);
     let cat = probability::distribution::Categorical::new(&probs[..]);
     let decider = Independent(&cat, &mut source);
     Some(decider.take(n).collect::<Vec<_>>())
@@ -108,19 +427,20 @@ pub fn select_wavs(wavs: &[WavDesc], n: usize) -> Option<Vec<usize>> {
 pub fn survey_wavs(
     worker_id: u32,
     cfg: PlayConfig,
+    catalog: Arc<Mutex<Catalog>>,
     paths_rx: Receiver<path::PathBuf>,
     wdescs_tx: Sender<Option<WavDesc>>,
     done_tx: Sender<u32>,
 ) {
     for path in paths_rx {
         if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if let Some(ext) = ext.to_str() {
-                    if ext.eq_ignore_ascii_case("wav") {
-                        if let Some(wdesc) = describe_wav(path, cfg.cap_ms) {
-                            println!("worker:{} sending for {:?}", worker_id, &wdesc.path);
-                            wdescs_tx.send(Some(wdesc)).unwrap();
-                        }
+            if let Some(format) = Format::from_path(&path) {
+                let policy = cfg.channel_policy(&path);
+                let measure = cfg.measures_loudness();
+                if let Some(probe) = catalog::probe(&catalog, &path, format, policy, measure) {
+                    if let Some(wdesc) = describe_wav(path, format, probe, cfg.cap_ms, policy) {
+                        println!("worker:{} sending for {:?}", worker_id, &wdesc.path);
+                        wdescs_tx.send(Some(wdesc)).unwrap();
                     }
                 }
             }
@@ -129,3 +449,60 @@ pub fn survey_wavs(
     wdescs_tx.send(None).unwrap();
     done_tx.send(worker_id).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn wav(name: &str, ms_for_choice: f32) -> WavDesc {
+        WavDesc {
+            path: path::PathBuf::from(name),
+            n_samples: 44100,
+            spec: hound::WavSpec {
+                channels: 2,
+                sample_rate: 44100,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            },
+            format: Format::Wav,
+            kind: SampleKind::Int(16),
+            channel_map: ChannelMap::Pair(0, 1),
+            ms_for_choice,
+            loudness: None,
+            energy: Arc::new(EnergyMap::default()),
+        }
+    }
+
+    #[test]
+    fn one_file_is_always_picked() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let picks = select_wavs(&[wav("a.wav", 1000.0)], 5, &mut rng);
+        assert_eq!(picks, Some(vec![0; 5]));
+    }
+
+    #[test]
+    fn files_without_length_are_not_picked() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let wavs = [wav("a.wav", 0.0), wav("b.wav", 1000.0), wav("c.wav", 0.0)];
+        let picks = select_wavs(&wavs, 20, &mut rng).unwrap();
+        assert!(picks.iter().all(|i| *i == 1), "picked {:?}", picks);
+        let wavs = [wav("a.wav", 0.0), wav("b.wav", 0.0)];
+        assert_eq!(select_wavs(&wavs, 20, &mut rng).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn pairs_reduce_only_multichannel_files() {
+        let pair = ChannelPolicy::Pair(2, 3);
+        assert_eq!(ChannelMap::resolve(1, pair), Some(ChannelMap::Mono));
+        assert_eq!(ChannelMap::resolve(2, pair), Some(ChannelMap::Pair(0, 1)));
+        assert_eq!(ChannelMap::resolve(4, pair), Some(ChannelMap::Pair(2, 3)));
+        assert_eq!(ChannelMap::resolve(3, pair), None);
+        let downmix = ChannelPolicy::Downmix;
+        assert_eq!(
+            ChannelMap::resolve(2, downmix),
+            Some(ChannelMap::Pair(0, 1))
+        );
+        assert_eq!(ChannelMap::resolve(4, downmix), Some(ChannelMap::Downmix));
+    }
+}
@@ -0,0 +1,172 @@
+use std::f32::consts::PI;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+pub const DEFAULT_GAUSSIAN_SIGMA: f32 = 0.4;
+pub const DEFAULT_TRAPEZOID_RAMP: f32 = 0.1;
+pub const DEFAULT_PERCUSSIVE_DECAY: f32 = 0.2;
+// Percussive grains still ramp up and down this fraction to avoid clicks.
+const PERCUSSIVE_EDGE: f32 = 0.01;
+const ENVELOPE_TABLE_LEN: usize = 4096;
+
+// The amplitude envelope of a grain over its length.
+// Parameters are fractions of the grain length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowShape {
+    Tukey(f32), // tapered fraction
+    Hann,
+    Gaussian(f32),       // standard deviation as a fraction of half the grain
+    Trapezoid(f32, f32), // attack, release
+    Percussive(f32),     // exponential decay time constant
+}
+
+impl WindowShape {
+    // The amplitude at x, from 0 at the grain's start to 1 at its end.
+    fn amplitude(&self, x: f32) -> f32 {
+        match *self {
+            // https://en.wikipedia.org/wiki/Window_function#Tukey_window
+            WindowShape::Tukey(alpha) => {
+                let n = x.min(1.0 - x);
+                if n < alpha / 2.0 {
+                    0.5 * (1.0 - (2.0 * PI * n / alpha).cos())
+                } else {
+                    1.0
+                }
+            }
+            WindowShape::Hann => 0.5 * (1.0 - (2.0 * PI * x).cos()),
+            // Shifted and scaled to reach zero at the ends.
+            WindowShape::Gaussian(sigma) => {
+                let g = |x: f32| (-0.5 * ((x - 0.5) / (sigma * 0.5)).powi(2)).exp();
+                let edge = g(0.0);
+                ((g(x) - edge) / (1.0 - edge)).max(0.0)
+            }
+            WindowShape::Trapezoid(attack, release) => {
+                // Ramps that would overlap share the grain in proportion.
+                let scale = (attack + release).max(1.0);
+                let (attack, release) = (attack / scale, release / scale);
+                if x < attack {
+                    x / attack
+                } else if x > 1.0 - release {
+                    (1.0 - x) / release
+                } else {
+                    1.0
+                }
+            }
+            WindowShape::Percussive(decay) => {
+                let attack = (x / PERCUSSIVE_EDGE).min(1.0);
+                let release = ((1.0 - x) / PERCUSSIVE_EDGE).min(1.0);
+                attack * release * (-(x - PERCUSSIVE_EDGE).max(0.0) / decay).exp()
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let fraction = |name: &str, v: f32| {
+            if v > 0.0 && v <= 1.0 {
+                Ok(())
+            } else {
+                Err(format!("the {} window parameter must be in (0, 1]", name))
+            }
+        };
+        match *self {
+            WindowShape::Tukey(alpha) => fraction("tukey", alpha),
+            WindowShape::Hann => Ok(()),
+            WindowShape::Gaussian(sigma) => fraction("gaussian", sigma),
+            WindowShape::Trapezoid(attack, release) => {
+                fraction("trapezoid", attack).and_then(|_| fraction("trapezoid", release))
+            }
+            WindowShape::Percussive(decay) => fraction("percussive", decay),
+        }
+    }
+}
+
+// Shapes are written as a name with optional parameters,
+// like hann, tukey:0.5, gaussian:0.4, trapezoid:0.05,0.5 or percussive:0.2.
+impl FromStr for WindowShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let args: Vec<f32> = match args {
+            Some(args) => args
+                .split(',')
+                .map(|a| a.trim().parse::<f32>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("ill formed window parameters in {:?}", s))?,
+            None => Vec::new(),
+        };
+        let arg = |i: usize, default: f32| args.get(i).copied().unwrap_or(default);
+        let (shape, max_args) = match name.to_ascii_lowercase().as_str() {
+            "tukey" => (
+                WindowShape::Tukey(arg(0, crate::grain::DEFAULT_TUKEY_WINDOW_ALPHA)),
+                1,
+            ),
+            "hann" => (WindowShape::Hann, 0),
+            "gaussian" => (WindowShape::Gaussian(arg(0, DEFAULT_GAUSSIAN_SIGMA)), 1),
+            "trapezoid" => (
+                WindowShape::Trapezoid(
+                    arg(0, DEFAULT_TRAPEZOID_RAMP),
+                    arg(1, arg(0, DEFAULT_TRAPEZOID_RAMP)),
+                ),
+                2,
+            ),
+            "percussive" => (WindowShape::Percussive(arg(0, DEFAULT_PERCUSSIVE_DECAY)), 1),
+            _ => {
+                return Err(format!(
+                    "window {:?} is not one of tukey, hann, gaussian, trapezoid or percussive",
+                    s
+                ))
+            }
+        };
+        if args.len() > max_args {
+            return Err(format!("too many window parameters in {:?}", s));
+        }
+        shape.validate()?;
+        Ok(shape)
+    }
+}
+
+impl Display for WindowShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowShape::Tukey(alpha) => write!(f, "tukey:{}", alpha),
+            WindowShape::Hann => write!(f, "hann"),
+            WindowShape::Gaussian(sigma) => write!(f, "gaussian:{}", sigma),
+            WindowShape::Trapezoid(attack, release) => {
+                write!(f, "trapezoid:{},{}", attack, release)
+            }
+            WindowShape::Percussive(decay) => write!(f, "percussive:{}", decay),
+        }
+    }
+}
+
+// A window shape sampled once, so grains of any length
+// are enveloped by interpolating the table instead of calling cos.
+pub struct Envelope {
+    table: Vec<f32>,
+}
+
+impl Envelope {
+    pub fn new(shape: WindowShape) -> Self {
+        let last = (ENVELOPE_TABLE_LEN - 1) as f32;
+        Envelope {
+            table: (0..ENVELOPE_TABLE_LEN)
+                .map(|i| shape.amplitude(i as f32 / last))
+                .collect(),
+        }
+    }
+
+    // The amplitude at frame pos of a grain len frames long.
+    pub fn amplitude(&self, pos: usize, len: usize) -> f32 {
+        if len < 2 {
+            return 0.0;
+        }
+        let x = pos as f32 / (len - 1) as f32 * (self.table.len() - 1) as f32;
+        let i = (x as usize).min(self.table.len() - 2);
+        let frac = x - i as f32;
+        self.table[i] + (self.table[i + 1] - self.table[i]) * frac
+    }
+}